@@ -6,6 +6,7 @@ use clap::SubCommand;
 use dinghy_lib::compiler::CompileMode;
 use dinghy_lib::BuildArgs;
 use std::ffi::OsString;
+use std::path::PathBuf;
 
 pub struct CargoDinghyCli {}
 
@@ -15,10 +16,18 @@ impl CargoDinghyCli {
         I: IntoIterator<Item = T>,
         T: Into<OsString> + Clone,
     {
+        let long_version = format!("{}\n{}", crate_version!(), dinghy_lib::version_details());
         {
             App::new("dinghy")
                 .version(crate_version!())
+                .long_version(&*long_version)
+                .bundle_namespace()
                 .device()
+                .device_timeout()
+                .ios_app_id()
+                .ios_keychain()
+                .force_platform()
+                .force_target()
                 .verbose()
                 .quiet()
                 .overlay()
@@ -46,11 +55,22 @@ impl CargoDinghyCli {
                         .features()
                         .no_default_features()
                         .no_run()
+                        .list_runnables()
                         .all_features()
                         .common_remote()
+                        .all_devices()
+                        .timings()
                         .target()
+                        .auto_add_target()
+                        .target_dir()
+                        .manifest_path()
+                        .cargo_config()
+                        .frozen()
+                        .locked()
+                        .offline()
                         .verbose()
                         .additional_args()
+                        .args_file()
                         .strip()
                         .bearded(),
                 )
@@ -67,25 +87,55 @@ impl CargoDinghyCli {
                         .test()
                         .bench()
                         .debug_or_release()
+                        .profile()
                         .features()
                         .all_features()
                         .no_default_features()
                         .target()
+                        .auto_add_target()
+                        .target_dir()
+                        .manifest_path()
+                        .cargo_config()
+                        .frozen()
+                        .locked()
+                        .offline()
                         .verbose()
                         .additional_args()
                         .strip()
                         .bearded(),
                 )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Check the platform and the device are usable"),
+                )
                 .subcommand(
                     SubCommand::with_name("clean")
-                        .about("Remove artifacts that cargo has generated in the past"),
+                        .about("Remove artifacts that cargo has generated in the past")
+                        .target_dir()
+                        .manifest_path()
+                        .cargo_config()
+                        .frozen()
+                        .locked()
+                        .offline(),
                 )
                 .subcommand(
                     SubCommand::with_name("devices").about(
                         "List devices that can be used with Dinghy for the selected platform",
                     ),
                 )
-                .subcommand(SubCommand::with_name("lldbproxy").about("Debug through lldb"))
+                .subcommand(
+                    SubCommand::with_name("env")
+                        .about("Print the environment dinghy sets up to build for the platform"),
+                )
+                .subcommand(
+                    SubCommand::with_name("lldbproxy")
+                        .about("Debug through lldb")
+                        .port(),
+                )
+                .subcommand(
+                    SubCommand::with_name("platforms")
+                        .about("Show how the platforms known to dinghy are configured"),
+                )
                 .subcommand(
                     SubCommand::with_name("run")
                         .about("Build and execute src/main.rs")
@@ -94,13 +144,24 @@ impl CargoDinghyCli {
                         .package()
                         .job()
                         .debug_or_release()
+                        .profile()
                         .features()
                         .all_features()
                         .no_default_features()
                         .target()
+                        .auto_add_target()
+                        .target_dir()
+                        .manifest_path()
+                        .cargo_config()
+                        .frozen()
+                        .locked()
+                        .offline()
                         .verbose()
                         .common_remote()
+                        .all_devices()
+                        .timings()
                         .additional_args()
+                        .args_file()
                         .strip()
                         .bearded(),
                 )
@@ -120,11 +181,24 @@ impl CargoDinghyCli {
                         .all_features()
                         .no_default_features()
                         .no_run()
+                        .list_runnables()
+                        .shard()
                         .debug_or_release()
+                        .profile()
                         .target()
+                        .auto_add_target()
+                        .target_dir()
+                        .manifest_path()
+                        .cargo_config()
+                        .frozen()
+                        .locked()
+                        .offline()
                         .verbose()
                         .common_remote()
+                        .all_devices()
+                        .timings()
                         .additional_args()
+                        .args_file()
                         .strip()
                         .bearded(),
                 )
@@ -140,7 +214,25 @@ impl CargoDinghyCli {
                 _ => CompileMode::Build,
             },
             forced_overlays: arg_as_string_vec(matches, "OVERLAY"),
+            bundle_manifest: match matches.subcommand() {
+                (_, Some(sub)) => sub.is_present("BUNDLE_MANIFEST"),
+                _ => false,
+            },
+            log_file: matches
+                .subcommand()
+                .1
+                .and_then(|sub| sub.value_of("LOG_FILE"))
+                .map(PathBuf::from),
+            trace: matches
+                .subcommand()
+                .1
+                .and_then(|sub| sub.value_of("TRACE"))
+                .map(|tool| tool.to_string()),
             verbose: matches.occurrences_of("VERBOSE") > 0,
+            verify_test_data: match matches.subcommand() {
+                (_, Some(sub)) => sub.is_present("VERIFY_TEST_DATA"),
+                _ => false,
+            },
         }
     }
 }
@@ -148,26 +240,46 @@ impl CargoDinghyCli {
 pub trait CargoDinghyCliExt {
     fn additional_args(self) -> Self;
     fn all(self) -> Self;
+    fn all_devices(self) -> Self;
     fn all_features(self) -> Self;
+    fn args_file(self) -> Self;
+    fn auto_add_target(self) -> Self;
     fn bin(self) -> Self;
     fn bench(self) -> Self;
+    fn bundle_namespace(self) -> Self;
+    fn cargo_config(self) -> Self;
     fn common_remote(self) -> Self;
     fn device(self) -> Self;
+    fn device_timeout(self) -> Self;
     fn example(self) -> Self;
     fn exclude(self) -> Self;
     fn exe(self) -> Self;
+    fn force_platform(self) -> Self;
+    fn force_target(self) -> Self;
+    fn ios_app_id(self) -> Self;
+    fn ios_keychain(self) -> Self;
     fn features(self) -> Self;
+    fn frozen(self) -> Self;
     fn job(self) -> Self;
     fn lib(self) -> Self;
+    fn list_runnables(self) -> Self;
+    fn locked(self) -> Self;
+    fn manifest_path(self) -> Self;
     fn no_default_features(self) -> Self;
     fn no_run(self) -> Self;
+    fn offline(self) -> Self;
     fn overlay(self) -> Self;
     fn package(self) -> Self;
     fn platform(self) -> Self;
+    fn port(self) -> Self;
     fn debug_or_release(self) -> Self;
+    fn profile(self) -> Self;
+    fn shard(self) -> Self;
     fn strip(self) -> Self;
     fn target(self) -> Self;
+    fn target_dir(self) -> Self;
     fn test(self) -> Self;
+    fn timings(self) -> Self;
     fn verbose(self) -> Self;
     fn quiet(self) -> Self;
     fn bearded(self) -> Self;
@@ -175,7 +287,11 @@ pub trait CargoDinghyCliExt {
 
 impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
     fn additional_args(self) -> Self {
-        self.arg(Arg::with_name("ARGS").multiple(true).help("test arguments"))
+        self.arg(
+            Arg::with_name("ARGS")
+                .multiple(true)
+                .help("Arguments for the runnables, everything after -- is passed verbatim"),
+        )
     }
 
     fn all(self) -> Self {
@@ -186,6 +302,24 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn all_devices(self) -> Self {
+        self.arg(
+            Arg::with_name("ALL_DEVICES")
+                .long("all-devices")
+                .conflicts_with("DEBUGGER")
+                .help(
+                    "Run on all the devices compatible with the platform (and matching --device)",
+                ),
+        )
+        .arg(
+            Arg::with_name("DEVICE_PARALLELISM")
+                .long("device-parallelism")
+                .takes_value(true)
+                .requires("ALL_DEVICES")
+                .help("Number of devices to run on concurrently with --all-devices (default 1)"),
+        )
+    }
+
     fn all_features(self) -> Self {
         self.arg(
             Arg::with_name("ALL_FEATURES")
@@ -194,6 +328,24 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn args_file(self) -> Self {
+        self.arg(
+            Arg::with_name("ARGS_FILE")
+                .long("args-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Append the lines of PATH to the arguments for the runnables, one argument per line"),
+        )
+    }
+
+    fn auto_add_target(self) -> Self {
+        self.arg(
+            Arg::with_name("AUTO_ADD_TARGET")
+                .long("auto-add-target")
+                .help("install the standard library of the target with rustup if it is missing"),
+        )
+    }
+
     fn bench(self) -> Self {
         self.arg(
             Arg::with_name("BENCH")
@@ -212,8 +364,29 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn cargo_config(self) -> Self {
+        self.arg(
+            Arg::with_name("CARGO_CONFIG")
+                .long("cargo-config")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help(
+                    "override a cargo configuration value, like cargo --config \
+                     (also allows nightly-only cargo features, even on stable)",
+                ),
+        )
+    }
+
     fn common_remote(self) -> Self {
         self.arg(
+            Arg::with_name("BUNDLE_MANIFEST")
+                .long("bundle-manifest")
+                .takes_value(false)
+                .help("write and print the list of bundled files with their sizes"),
+        )
+        .arg(
             Arg::with_name("CLEANUP")
                 .long("cleanup")
                 .takes_value(false)
@@ -232,6 +405,56 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
                 .multiple(true)
                 .help("Space-separated list of env variables to set e.g. RUST_TRACE=trace"),
         )
+        .arg(
+            Arg::with_name("INSTALL_ONLY")
+                .long("install-only")
+                .takes_value(false)
+                .conflicts_with("DEBUGGER")
+                .help("install the runnables and print how to run them, without running them"),
+        )
+        .arg(
+            Arg::with_name("KEEP_BUNDLE")
+                .long("keep-bundle")
+                .takes_value(false)
+                .help("print the paths of the host bundles, to inspect what was deployed"),
+        )
+        .arg(
+            Arg::with_name("LOG_FILE")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("also write the output of the runnables to PATH, {runnable} and {device} are replaced by their ids"),
+        )
+        .arg(
+            Arg::with_name("NO_BACKTRACE")
+                .long("no-backtrace")
+                .takes_value(false)
+                .help("don't enable backtraces (RUST_BACKTRACE=1) in the runnables"),
+        )
+        .arg(
+            Arg::with_name("TRACE")
+                .long("trace")
+                .takes_value(true)
+                .value_name("TOOL")
+                .possible_values(&["strace", "ltrace"])
+                .help("run under strace or ltrace on the device, traces go to target/dinghy-traces"),
+        )
+        .arg(
+            Arg::with_name("VERIFY_TEST_DATA")
+                .long("verify-test-data")
+                .takes_value(false)
+                .help("compare the sha256 of the verified_test_data files on the device"),
+        )
+    }
+
+    fn bundle_namespace(self) -> Self {
+        self.arg(
+            Arg::with_name("BUNDLE_NAMESPACE")
+                .long("bundle-namespace")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Directory name of the bundles on the host and the devices, instead of 'dinghy'"),
+        )
     }
 
     fn device(self) -> Self {
@@ -244,6 +467,15 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn device_timeout(self) -> Self {
+        self.arg(
+            Arg::with_name("DEVICE_TIMEOUT")
+                .long("device-timeout")
+                .takes_value(true)
+                .help("Seconds to wait for device discovery, slower discoveries are skipped"),
+        )
+    }
+
     fn example(self) -> Self {
         self.arg(
             Arg::with_name("EXAMPLE")
@@ -273,12 +505,61 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn force_platform(self) -> Self {
+        self.arg(
+            Arg::with_name("FORCE_PLATFORM")
+                .long("force-platform")
+                .takes_value(false)
+                .requires_all(&["PLATFORM", "DEVICE"])
+                .help("Use --platform on the --device even if they look incompatible"),
+        )
+    }
+
+    fn force_target(self) -> Self {
+        self.arg(
+            Arg::with_name("FORCE_TARGET")
+                .long("force-target")
+                .takes_value(false)
+                .requires("DEVICE")
+                .help(
+                    "Use the platform of --target on the --device even if they look incompatible",
+                ),
+        )
+    }
+
     fn features(self) -> Self {
         self.arg(
             Arg::with_name("FEATURES")
                 .long("features")
                 .takes_value(true)
-                .help("Space-separated list of features to also build"),
+                .help("Space or comma separated list of features to also build"),
+        )
+    }
+
+    fn frozen(self) -> Self {
+        self.arg(
+            Arg::with_name("FROZEN")
+                .long("frozen")
+                .help("Require Cargo.lock and cache are up to date"),
+        )
+    }
+
+    fn ios_app_id(self) -> Self {
+        self.arg(
+            Arg::with_name("IOS_APP_ID")
+                .long("ios-app-id")
+                .takes_value(true)
+                .help("App id to sign iOS test apps with (matched against provisioning profiles)"),
+        )
+    }
+
+    fn ios_keychain(self) -> Self {
+        self.arg(
+            Arg::with_name("IOS_KEYCHAIN")
+                .long("keychain")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Keychain holding the identity to sign iOS test apps with"),
         )
     }
 
@@ -296,6 +577,32 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         self.arg(Arg::with_name("LIB").long("lib").help("only the library"))
     }
 
+    fn list_runnables(self) -> Self {
+        self.arg(
+            Arg::with_name("LIST_RUNNABLES")
+                .long("list-runnables")
+                .conflicts_with("NO_RUN")
+                .help("Compile, then list the runnables (name, package root and executable) without running them"),
+        )
+    }
+
+    fn locked(self) -> Self {
+        self.arg(
+            Arg::with_name("LOCKED")
+                .long("locked")
+                .help("Require Cargo.lock is up to date"),
+        )
+    }
+
+    fn manifest_path(self) -> Self {
+        self.arg(
+            Arg::with_name("MANIFEST_PATH")
+                .long("manifest-path")
+                .takes_value(true)
+                .help("Path to the Cargo.toml of the package to build"),
+        )
+    }
+
     fn no_default_features(self) -> Self {
         self.arg(
             Arg::with_name("NO_DEFAULT_FEATURES")
@@ -308,7 +615,34 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         self.arg(
             Arg::with_name("NO_RUN")
                 .long("no-run")
-                .help("Compile, but don't run tests or benches"),
+                .help("Compile, but don't run tests or benches (prints the built executables)"),
+        )
+    }
+
+    fn offline(self) -> Self {
+        self.arg(
+            Arg::with_name("OFFLINE")
+                .long("offline")
+                .help("Run without accessing the network"),
+        )
+    }
+
+    fn shard(self) -> Self {
+        self.arg(
+            Arg::with_name("SHARD_INDEX")
+                .long("shard-index")
+                .takes_value(true)
+                .value_name("I")
+                .requires("SHARD_COUNT")
+                .help("only run shard I (from 0) of the runnables, see --shard-count"),
+        )
+        .arg(
+            Arg::with_name("SHARD_COUNT")
+                .long("shard-count")
+                .takes_value(true)
+                .value_name("N")
+                .requires("SHARD_INDEX")
+                .help("split the runnables in N shards dealt by name, for distributed runs"),
         )
     }
 
@@ -319,6 +653,12 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
                 .takes_value(false)
                 .help("strip the final executable (will have '-stripped' extension)"),
         )
+        .arg(
+            Arg::with_name("DEBUG_FILES")
+                .long("debug-files")
+                .requires("STRIP")
+                .help("with --strip, first save the debug symbols to a '.debug' file next to the executable"),
+        )
     }
 
     fn package(self) -> Self {
@@ -354,6 +694,15 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn port(self) -> Self {
+        self.arg(
+            Arg::with_name("PORT")
+                .long("port")
+                .takes_value(true)
+                .help("Local port the lldb proxy will listen on"),
+        )
+    }
+
     fn debug_or_release(self) -> Self {
         self.arg(
             Arg::with_name("RELEASE")
@@ -372,12 +721,34 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn profile(self) -> Self {
+        self.arg(
+            Arg::with_name("PROFILE")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("BUILD_TYPE")
+                .help("Build artifacts with the specified cargo profile"),
+        )
+    }
+
     fn target(self) -> Self {
         self.arg(
             Arg::with_name("TARGET")
                 .long("target")
                 .takes_value(true)
-                .help("target triple (rust conventions)"),
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .help("target triple (rust conventions), several can be built in sequence"),
+        )
+    }
+
+    fn target_dir(self) -> Self {
+        self.arg(
+            Arg::with_name("TARGET_DIR")
+                .long("target-dir")
+                .takes_value(true)
+                .help("Directory for all generated artifacts"),
         )
     }
 
@@ -390,6 +761,14 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
         )
     }
 
+    fn timings(self) -> Self {
+        self.arg(
+            Arg::with_name("TIMINGS")
+                .long("timings")
+                .help("Print how long each runnable took to install and run"),
+        )
+    }
+
     fn verbose(self) -> Self {
         self.arg(
             Arg::with_name("VERBOSE")
@@ -406,7 +785,7 @@ impl<'a, 'b> CargoDinghyCliExt for App<'a, 'b> {
                 .short("q")
                 .long("quiet")
                 .multiple(true)
-                .help("Lower the level of verbosity"),
+                .help("Only log dinghy errors, cargo output is unchanged"),
         )
     }
 
@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
 extern crate dinghy_lib;
 extern crate env_logger;
 #[macro_use]
@@ -9,19 +10,30 @@ use crate::cli::CargoDinghyCli;
 use clap::ArgMatches;
 use dinghy_lib::compiler::Compiler;
 use dinghy_lib::config::dinghy_config;
+use dinghy_lib::config::Configuration;
+use dinghy_lib::device::add_backtrace_env;
+use dinghy_lib::device::prefix_output;
 use dinghy_lib::errors::*;
-use dinghy_lib::itertools::Itertools;
 use dinghy_lib::project::Project;
 use dinghy_lib::utils::arg_as_string_vec;
 use dinghy_lib::Build;
+use dinghy_lib::BuildBundle;
 use dinghy_lib::Device;
+use dinghy_lib::DeviceCheck;
+use dinghy_lib::DeviceKind;
 use dinghy_lib::Dinghy;
 use dinghy_lib::Platform;
+use std::collections::BTreeMap;
 use std::env;
 use std::env::current_dir;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
-use std::time;
+use std::time::Duration;
+use std::time::Instant;
 
 mod cli;
 
@@ -33,12 +45,16 @@ fn main() {
     let matches = CargoDinghyCli::parse(filtered_args);
 
     if env::var("RUST_LOG").is_err() {
-        let dinghy_verbosity =
-            match matches.occurrences_of("VERBOSE") - matches.occurrences_of("QUIET") {
-                0 => "info",
-                1 => "debug",
-                _ => "trace",
-            };
+        // -q only silences dinghy's own logs, and an explicit -v wins over it
+        let dinghy_verbosity = match (
+            matches.occurrences_of("VERBOSE"),
+            matches.occurrences_of("QUIET"),
+        ) {
+            (0, 0) => "info",
+            (0, _) => "error",
+            (1, _) => "debug",
+            _ => "trace",
+        };
         env::set_var(
             "RUST_LOG",
             format!(
@@ -51,27 +67,66 @@ fn main() {
 
     if let Err(e) = run_command(&matches) {
         error!("{:?}", e);
-        // positively ugly.
-        if e.to_string().contains("are filtered out on platform") {
-            std::process::exit(3)
-        } else {
-            std::process::exit(1)
+        match e.downcast_ref::<DinghyError>() {
+            Some(DinghyError::PackagesFilteredOut { .. }) => std::process::exit(3),
+            _ => std::process::exit(1),
         }
     }
 }
 
 fn run_command(args: &ArgMatches) -> Result<()> {
-    let conf = Arc::new(dinghy_config(current_dir().unwrap())?);
+    // Paths given on the command line are relative to where dinghy was started
+    let invocation_dir = current_dir()?;
+    // Both cargo and the toolchain shims read it, so they agree on the target dir
+    if let Some(target_dir) = args.subcommand().1.and_then(|it| it.value_of("TARGET_DIR")) {
+        env::set_var("CARGO_TARGET_DIR", invocation_dir.join(target_dir));
+    }
+    // Everything (configuration, workspace, target dir) is looked up from the
+    // working directory, so build from the manifest directory
+    if let Some(manifest_path) = args
+        .subcommand()
+        .1
+        .and_then(|it| it.value_of("MANIFEST_PATH"))
+    {
+        let mut manifest_path = PathBuf::from(manifest_path);
+        if manifest_path.is_dir() {
+            manifest_path.push("Cargo.toml");
+        }
+        if !manifest_path.is_file() || manifest_path.file_name() != Some("Cargo.toml".as_ref()) {
+            bail!("No Cargo.toml found at {}", manifest_path.display())
+        }
+        let manifest_dir = match manifest_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        env::set_current_dir(manifest_dir)
+            .with_context(|| format!("Couldn't change directory to {}", manifest_dir.display()))?;
+    }
+    let mut conf = dinghy_config(current_dir().unwrap())?;
+    if let Some(bundle_namespace) = args.value_of("BUNDLE_NAMESPACE") {
+        conf.bundle_namespace = Some(bundle_namespace.to_string());
+    }
+    if let Some(device_timeout) = args.value_of("DEVICE_TIMEOUT") {
+        conf.device_timeout = Some(device_timeout.parse()?);
+    }
+    if let Some(ios_app_id) = args.value_of("IOS_APP_ID") {
+        conf.ios_app_id = Some(ios_app_id.to_string());
+    }
+    if let Some(ios_keychain) = args.value_of("IOS_KEYCHAIN") {
+        conf.ios_keychain = Some(ios_keychain.to_string());
+    }
+    let conf = Arc::new(conf);
     let compiler = Arc::new(Compiler::from_args(args.subcommand().1.unwrap_or(args))?);
     let dinghy = Dinghy::probe(&conf, &compiler)?;
     let project = Project::new(&conf);
     match args.subcommand() {
         ("all-devices", Some(_)) => return show_all_devices(&dinghy),
         ("all-platforms", Some(_)) => return show_all_platforms(&dinghy),
+        ("platforms", Some(_)) => return show_platforms_details(&dinghy),
         _ => {}
     };
 
-    let (platform, device) = select_platform_and_device_from_cli(&args, &dinghy)?;
+    let (platform, device) = select_platform_and_device_from_cli(&args, &dinghy, &conf)?;
     info!(
         "Targeting platform '{}' and device '{}'",
         platform.id(),
@@ -79,13 +134,41 @@ fn run_command(args: &ArgMatches) -> Result<()> {
     );
 
     match args.subcommand() {
-        ("bench", Some(sub_args)) => prepare_and_run(device, project, platform, args, sub_args),
-        ("build", Some(sub_args)) => build(&platform, &project, args, sub_args).and(Ok(())),
+        ("bench", Some(sub_args)) => prepare_and_run(
+            &dinghy,
+            device,
+            project,
+            platform,
+            args,
+            sub_args,
+            &invocation_dir,
+        ),
+        ("build", Some(sub_args)) => {
+            build_targets(&dinghy, platform, &project, args, sub_args, &invocation_dir)
+        }
+        ("check", Some(_)) => check(device, &platform),
         ("clean", Some(_)) => compiler.clean(&**platform),
         ("devices", Some(_)) => show_all_devices_for_platform(&dinghy, platform),
-        ("lldbproxy", Some(_)) => run_lldb(device),
-        ("run", Some(sub_args)) => prepare_and_run(device, project, platform, args, sub_args),
-        ("test", Some(sub_args)) => prepare_and_run(device, project, platform, args, sub_args),
+        ("env", Some(_)) => show_env(&**platform, &project, args),
+        ("lldbproxy", Some(sub_args)) => run_lldb(device, &platform, sub_args),
+        ("run", Some(sub_args)) => prepare_and_run(
+            &dinghy,
+            device,
+            project,
+            platform,
+            args,
+            sub_args,
+            &invocation_dir,
+        ),
+        ("test", Some(sub_args)) => prepare_and_run(
+            &dinghy,
+            device,
+            project,
+            platform,
+            args,
+            sub_args,
+            &invocation_dir,
+        ),
         (sub, _) => bail!("Unknown dinghy command '{}'", sub),
     }
 }
@@ -95,44 +178,170 @@ fn build(
     project: &Project,
     args: &ArgMatches,
     sub_args: &ArgMatches,
+    invocation_dir: &Path,
 ) -> Result<Build> {
-    let build_args = CargoDinghyCli::build_args_from(args);
+    let mut build_args = CargoDinghyCli::build_args_from(args);
+    build_args.log_file = build_args.log_file.map(|it| invocation_dir.join(it));
     let build = platform.build(&project, &build_args)?;
 
     if sub_args.is_present("STRIP") {
-        platform.strip(&build)?;
+        platform.strip(&build, sub_args.is_present("DEBUG_FILES"))?;
     }
     Ok(build)
 }
 
+fn build_targets(
+    dinghy: &Dinghy,
+    platform: Arc<Box<dyn Platform>>,
+    project: &Project,
+    args: &ArgMatches,
+    sub_args: &ArgMatches,
+    invocation_dir: &Path,
+) -> Result<()> {
+    let targets = arg_as_string_vec(sub_args, "TARGET");
+    if targets.is_empty() {
+        return build(&platform, project, args, sub_args, invocation_dir).and(Ok(()));
+    }
+    let platforms = targets
+        .iter()
+        .map(|target| platform_for_target(dinghy, target))
+        .collect::<Result<Vec<_>>>()?;
+    for (target, platform) in targets.iter().zip(platforms) {
+        info!("Building for platform '{}'", platform.id());
+        let build = build(&platform, project, args, sub_args, invocation_dir)?;
+        for runnable in &build.runnables {
+            info!("Built {} for {}", runnable.exe.display(), target);
+        }
+    }
+    Ok(())
+}
+
 fn prepare_and_run(
+    dinghy: &Dinghy,
     device: Option<Arc<Box<dyn Device>>>,
     project: Project,
     platform: Arc<Box<dyn Platform>>,
     args: &ArgMatches,
     sub_args: &ArgMatches,
+    invocation_dir: &Path,
 ) -> Result<()> {
     debug!("Build for {}", platform);
-    let build = build(&platform.clone(), &project, args, sub_args)?;
+    let mut build = build(&platform.clone(), &project, args, sub_args, invocation_dir)?;
+    if let (Some(index), Some(count)) = (
+        sub_args.value_of("SHARD_INDEX"),
+        sub_args.value_of("SHARD_COUNT"),
+    ) {
+        build.shard(index.parse()?, count.parse()?)?;
+    }
 
     if sub_args.is_present("NO_RUN") {
+        for runnable in &build.runnables {
+            println!("{}", runnable.exe.display());
+        }
         return Ok(());
     }
+    if sub_args.is_present("LIST_RUNNABLES") {
+        for runnable in &build.runnables {
+            println!(
+                "{}\t{}\t{}",
+                runnable.id,
+                runnable.source.display(),
+                runnable.exe.display()
+            );
+        }
+        return Ok(());
+    }
+
+    // --env comes last, so it overrides the platform runtime environment
+    let mut envs = platform
+        .runtime_env()
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .chain(arg_as_string_vec(sub_args, "ENVS"))
+        .collect::<Vec<_>>();
+    // Runnables on the host inherit our environment, so a shell export wins
+    add_backtrace_env(
+        &mut envs,
+        sub_args.is_present("NO_BACKTRACE"),
+        platform.is_host() && env::var_os("RUST_BACKTRACE").is_some(),
+    );
+    let runnable_args = runnable_args(sub_args, invocation_dir)?;
+
+    if sub_args.is_present("ALL_DEVICES") {
+        let devices = dinghy
+            .find_devices(args.value_of("DEVICE"))
+            .into_iter()
+            .filter(|it| platform.is_compatible_with(&***it))
+            .collect::<Vec<_>>();
+        return run_on_devices(&devices, &project, &build, &runnable_args, &envs, sub_args);
+    }
 
     debug!("Run on {:?}", device);
-    let device = device.ok_or_else(|| anyhow!("No device found"))?;
-    let args = arg_as_string_vec(sub_args, "ARGS");
-    let envs = arg_as_string_vec(sub_args, "ENVS");
+    let device = device.ok_or(DinghyError::DeviceNotFound(None))?;
+    run_on_device(&**device, &project, &build, &runnable_args, &envs, sub_args)
+}
 
+/// The arguments after `--`, followed by the lines of `--args-file`.
+fn runnable_args(sub_args: &ArgMatches, invocation_dir: &Path) -> Result<Vec<String>> {
+    let mut args = arg_as_string_vec(sub_args, "ARGS");
+    if let Some(file) = sub_args.value_of("ARGS_FILE") {
+        let content = std::fs::read_to_string(invocation_dir.join(file))
+            .with_context(|| format!("Couldn't read arguments file {}", file))?;
+        args.extend(
+            content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string()),
+        );
+    }
+    Ok(args)
+}
+
+fn run_on_device(
+    device: &dyn Device,
+    project: &Project,
+    build: &Build,
+    args: &[String],
+    envs: &[String],
+    sub_args: &ArgMatches,
+) -> Result<()> {
+    if build.build_args.trace.is_some()
+        && !matches!(device.kind(), DeviceKind::Android | DeviceKind::Ssh)
+    {
+        bail!(
+            "--trace is only supported on android and ssh devices, not on {}",
+            device.id()
+        )
+    }
     let args = args.iter().map(|s| &s[..]).collect::<Vec<_>>();
     let envs = envs.iter().map(|s| &s[..]).collect::<Vec<_>>();
+    if sub_args.is_present("INSTALL_ONLY") {
+        debug!("Install app");
+        for (remote_bundle, command) in device.install_runnables(project, build, &args, &envs)? {
+            println!(
+                "{} installed at {}",
+                remote_bundle.id,
+                remote_bundle.bundle_exe.display()
+            );
+            println!("{}", command);
+        }
+        return Ok(());
+    }
     let build_bundles = if sub_args.is_present("DEBUGGER") {
         debug!("Debug app");
-        vec![device.debug_app(&project, &build, &*args, &*envs)?]
+        vec![device.debug_app(project, build, &*args, &*envs)?]
+    } else if sub_args.is_present("TIMINGS") {
+        debug!("Run app with timings");
+        run_with_timings(device, project, build, &args, &envs)?
     } else {
         debug!("Run app");
-        device.run_app(&project, &build, &*args, &*envs)?
+        device.run_app(project, build, &*args, &*envs)?.0
     };
+    if sub_args.is_present("KEEP_BUNDLE") {
+        for build_bundle in &build_bundles {
+            println!("{}", build_bundle.bundle_dir.display());
+        }
+    }
 
     if sub_args.is_present("CLEANUP") {
         for build_bundle in build_bundles {
@@ -142,15 +351,211 @@ fn prepare_and_run(
     Ok(())
 }
 
-fn run_lldb(device: Option<Arc<Box<dyn Device>>>) -> Result<()> {
-    let device = device.ok_or_else(|| anyhow!("No device found"))?;
-    let lldb = device.start_remote_lldb()?;
-    info!("lldb running at: {}", lldb);
-    loop {
-        thread::sleep(time::Duration::from_millis(100));
+/// Runs the build on each device, on up to `--device-parallelism` devices at
+/// a time, and returns the first failure once all devices are done.
+fn run_on_devices(
+    devices: &[Arc<Box<dyn Device>>],
+    project: &Project,
+    build: &Build,
+    args: &[String],
+    envs: &[String],
+    sub_args: &ArgMatches,
+) -> Result<()> {
+    if devices.is_empty() {
+        bail!(DinghyError::DeviceNotFound(None))
+    }
+    let parallelism = sub_args
+        .value_of("DEVICE_PARALLELISM")
+        .map(|it| it.parse::<usize>())
+        .transpose()?
+        .unwrap_or(1)
+        .max(1);
+    info!(
+        "Running on {} device(s), {} at a time",
+        devices.len(),
+        parallelism
+    );
+
+    let queue = Mutex::new(devices.iter());
+    let results = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        for _ in 0..parallelism.min(devices.len()) {
+            scope.spawn(|| loop {
+                let device = match queue.lock().map(|mut it| it.next()) {
+                    Ok(Some(device)) => device,
+                    _ => break,
+                };
+                info!("Run on {}", device.id());
+                if parallelism > 1 {
+                    prefix_output(Some(format!("[{}] ", device.id())));
+                }
+                let start = Instant::now();
+                let result = run_on_device(&***device, project, build, args, envs, sub_args);
+                if let Err(e) = &result {
+                    error!("{} failed: {:?}", device.id(), e);
+                }
+                if let Ok(mut results) = results.lock() {
+                    results.push((device.id().to_string(), start.elapsed(), result));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().map_err(|_| anyhow!("poisoned lock"))?;
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    let width = results
+        .iter()
+        .map(|it| it.0.len())
+        .max()
+        .unwrap_or(0)
+        .max("device".len());
+    println!("{:width$}  {:>10}  result", "device", "time", width = width);
+    for (id, duration, result) in &results {
+        println!(
+            "{:width$}  {:>9.2}s  {}",
+            id,
+            duration.as_secs_f64(),
+            if result.is_ok() { "ok" } else { "FAILED" },
+            width = width
+        );
+    }
+    match results.into_iter().find_map(|it| it.2.err()) {
+        Some(e) => Err(e),
+        None => Ok(()),
     }
 }
 
+fn run_with_timings(
+    device: &dyn Device,
+    project: &Project,
+    build: &Build,
+    args: &[&str],
+    envs: &[&str],
+) -> Result<Vec<BuildBundle>> {
+    let mut build_bundles = vec![];
+    let mut timings = vec![];
+    let mut first_error = None;
+    for runnable in &build.runnables {
+        let single_build = Build {
+            runnables: vec![runnable.clone()],
+            ..build.clone()
+        };
+        let start = Instant::now();
+        let result = device.run_app(project, &single_build, args, envs);
+        // A failed run does not tell how long its install took
+        let install = result.as_ref().ok().map(|it| it.1);
+        timings.push((
+            runnable.id.clone(),
+            install,
+            start.elapsed(),
+            result.is_ok(),
+        ));
+        match result {
+            Ok((bundles, _)) => build_bundles.extend(bundles),
+            Err(e) => {
+                error!("{} failed: {:?}", runnable.id, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    let width = timings.iter().map(|it| it.0.len()).max().unwrap_or(0);
+    println!(
+        "{:width$}  {:>10}  {:>10}  {:>10}  result",
+        "runnable",
+        "install",
+        "run",
+        "total",
+        width = width
+    );
+    let seconds = |duration: Option<Duration>| match duration {
+        Some(duration) => format!("{:.2}s", duration.as_secs_f64()),
+        None => "-".to_string(),
+    };
+    for (id, install, total, ok) in &timings {
+        println!(
+            "{:width$}  {:>10}  {:>10}  {:>10}  {}",
+            id,
+            seconds(*install),
+            seconds(install.map(|install| *total - install)),
+            seconds(Some(*total)),
+            if *ok { "ok" } else { "FAILED" },
+            width = width
+        );
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(build_bundles),
+    }
+}
+
+fn check(device: Option<Arc<Box<dyn Device>>>, platform: &Arc<Box<dyn Platform>>) -> Result<()> {
+    let device = device.ok_or(DinghyError::DeviceNotFound(None))?;
+    let mut checks = vec![DeviceCheck::new(
+        format!("platform {} toolchain", platform.id()),
+        true,
+        platform.sysroot().map(|_| ()),
+    )];
+    checks.extend(device.check());
+
+    println!(
+        "Checking device '{}' for platform '{}':",
+        device.id(),
+        platform.id()
+    );
+    let mut failed = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("  [ok]   {}", check.name),
+            Err(e) if check.critical => {
+                failed += 1;
+                println!("  [FAIL] {}: {}", check.name, e)
+            }
+            Err(e) => println!("  [warn] {}: {}", check.name, e),
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} critical check(s) failed on device '{}'",
+            failed,
+            device.id()
+        )
+    }
+    Ok(())
+}
+
+fn run_lldb(
+    device: Option<Arc<Box<dyn Device>>>,
+    platform: &Arc<Box<dyn Platform>>,
+    sub_args: &ArgMatches,
+) -> Result<()> {
+    let device = device.ok_or(DinghyError::DeviceNotFound(None))?;
+    let port = sub_args
+        .value_of("PORT")
+        .map(|it| it.parse::<u16>())
+        .transpose()?;
+    let lldb = device.start_remote_lldb(port)?;
+    info!("lldb running at: {}", lldb);
+    let (lldb_platform, connect) = if platform.rustc_triple().contains("apple-ios") {
+        ("remote-ios", "process connect")
+    } else if platform.rustc_triple().contains("android") {
+        ("remote-android", "platform connect")
+    } else {
+        ("remote-linux", "platform connect")
+    };
+    println!("platform select {}", lldb_platform);
+    println!("{} connect://{}", connect, lldb);
+
+    let (stop_tx, stop_rx) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = stop_tx.send(());
+    })?;
+    stop_rx.recv()?;
+    info!("Stopping lldb proxy");
+    device.stop_remote_lldb()
+}
+
 fn show_all_platforms(dinghy: &Dinghy) -> Result<()> {
     let mut platforms = dinghy.platforms();
     platforms.sort_by(|str1, str2| str1.id().cmp(&str2.id()));
@@ -164,6 +569,57 @@ fn show_all_platforms(dinghy: &Dinghy) -> Result<()> {
     Ok(())
 }
 
+fn show_platforms_details(dinghy: &Dinghy) -> Result<()> {
+    let mut platforms = dinghy.platforms();
+    platforms.sort_by_key(|it| it.id());
+    for pf in platforms.iter() {
+        println!("* {} ({})", pf.id(), pf.rustc_triple());
+        println!(
+            "    toolchain: {}",
+            pf.toolchain_root()
+                .map(|it| it.display().to_string())
+                .unwrap_or_else(|| "<host>".to_string())
+        );
+        match pf.sysroot() {
+            Ok(Some(sysroot)) => println!("    sysroot:   {}", sysroot.display()),
+            Ok(None) => println!("    sysroot:   <none>"),
+            Err(e) => println!("    sysroot:   discovery failed: {}", e),
+        }
+        let mut overlays = pf
+            .configuration()
+            .overlays
+            .iter()
+            .flatten()
+            .map(|(id, overlay)| format!("{} ({})", id, overlay.path))
+            .collect::<Vec<_>>();
+        overlays.sort();
+        if overlays.is_empty() {
+            println!("    overlays:  <none>");
+        } else {
+            println!("    overlays:  {}", overlays.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Prints, as `KEY=VALUE` lines, the variables the platform setup adds or
+/// changes in the environment.
+fn show_env(platform: &dyn Platform, project: &Project, args: &ArgMatches) -> Result<()> {
+    let before = env::vars_os().collect::<BTreeMap<_, _>>();
+    platform.setup_env(project, &CargoDinghyCli::build_args_from(args))?;
+    Compiler::setup_build_script_env(platform)?;
+    for (key, value) in env::vars_os().collect::<BTreeMap<_, _>>() {
+        if before.get(&key) != Some(&value) {
+            println!(
+                "{}={}",
+                key.to_string_lossy(),
+                shell_escape::escape(value.to_string_lossy())
+            );
+        }
+    }
+    Ok(())
+}
+
 fn show_all_devices(dinghy: &Dinghy) -> Result<()> {
     println!("List of available devices for all platforms:");
     show_devices(&dinghy, None)
@@ -205,48 +661,98 @@ fn show_devices(dinghy: &Dinghy, platform: Option<Arc<Box<dyn Platform>>>) -> Re
     Ok(())
 }
 
+fn is_banned_auto_platform_id(id: &str) -> bool {
+    id.contains("auto-android")
+        && (id.contains("min") || id.contains("latest") || id.contains("api"))
+}
+
+fn platform_for_target(dinghy: &Dinghy, target: &str) -> Result<Arc<Box<dyn Platform>>> {
+    dinghy
+        .platforms()
+        .into_iter()
+        .filter(|pf| !is_banned_auto_platform_id(&pf.id()))
+        .find(|pf| pf.rustc_triple() == target)
+        .ok_or_else(|| anyhow!("No platform found for target {}", target))
+}
+
+type PlatformAndDevice = (Arc<Box<dyn Platform>>, Option<Arc<Box<dyn Device>>>);
+
 fn select_platform_and_device_from_cli(
     matches: &ArgMatches,
     dinghy: &Dinghy,
-) -> Result<(Arc<Box<dyn Platform>>, Option<Arc<Box<dyn Device>>>)> {
-    if let Some(platform_name) = matches.value_of("PLATFORM") {
+    conf: &Configuration,
+) -> Result<PlatformAndDevice> {
+    // Without --platform, a single --target of the subcommand selects its platform
+    let targets = matches
+        .subcommand()
+        .1
+        .map(|it| arg_as_string_vec(it, "TARGET"))
+        .unwrap_or_default();
+    let target_platform = match (matches.value_of("PLATFORM"), &*targets) {
+        (None, [target]) => Some(platform_for_target(dinghy, target)?.id()),
+        _ => None,
+    };
+    let platform_name = matches.value_of("PLATFORM").or(target_platform.as_deref());
+    if let (None, Some(default_device)) = (matches.value_of("DEVICE"), &conf.default_device) {
+        match select_platform_and_device(dinghy, platform_name, Some(default_device), false) {
+            Ok(selected @ (_, Some(_))) => return Ok(selected),
+            Ok((_, None)) => warn!("Default device '{}' not found, ignoring it", default_device),
+            Err(e) if matches!(e.downcast_ref(), Some(DinghyError::DeviceNotFound(_))) => {
+                warn!("Default device '{}' not found, ignoring it", default_device)
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "Could not select default device '{}'",
+                    default_device
+                )))
+            }
+        }
+    }
+    select_platform_and_device(
+        dinghy,
+        platform_name,
+        matches.value_of("DEVICE"),
+        matches.is_present("FORCE_PLATFORM") || matches.is_present("FORCE_TARGET"),
+    )
+}
+
+fn select_platform_and_device(
+    dinghy: &Dinghy,
+    platform_name: Option<&str>,
+    device_filter: Option<&str>,
+    force_platform: bool,
+) -> Result<PlatformAndDevice> {
+    if let Some(platform_name) = platform_name {
         let platform = dinghy
             .platform_by_name(platform_name)
             .ok_or_else(|| anyhow!("No '{}' platform found", platform_name))?;
 
-        let device = dinghy
-            .devices()
-            .into_iter()
-            .filter(|device| {
-                matches
-                    .value_of("DEVICE")
-                    .map(|filter| {
-                        format!("{}", device)
-                            .to_lowercase()
-                            .contains(&filter.to_lowercase())
-                    })
-                    .unwrap_or(true)
-            })
+        // The host is only a fallback (through a runner wrapper) for other platforms
+        let host = dinghy.host_device();
+        let devices = dinghy.find_devices(device_filter);
+        let device = devices
+            .iter()
             .filter(|it| platform.is_compatible_with(&**it.as_ref()))
-            .next();
+            .min_by_key(|it| Arc::ptr_eq(it, &host))
+            .cloned();
+        if device.is_none() && device_filter.is_some() {
+            if let Some(reason) = devices
+                .iter()
+                .find_map(|it| platform.incompatibility_with(&**it.as_ref()))
+            {
+                if !force_platform {
+                    bail!(reason)
+                }
+                warn!("{}, forcing it anyway", reason);
+                return Ok((platform, devices.into_iter().next()));
+            }
+        }
 
         Ok((platform, device))
-    } else if let Some(device_filter) = matches.value_of("DEVICE") {
-        let is_banned_auto_platform_id = |id: &str| -> bool {
-            id.contains("auto-android")
-                && (id.contains("min") || id.contains("latest") || id.contains("api"))
-        };
-        let devices = dinghy
-            .devices()
-            .into_iter()
-            .filter(move |it| {
-                format!("{:?}", it)
-                    .to_lowercase()
-                    .contains(&device_filter.to_lowercase())
-            })
-            .collect_vec();
+    } else if let Some(device_filter) = device_filter {
+        let devices = dinghy.find_devices(Some(device_filter));
         if devices.len() == 0 {
-            bail!("No devices found for name hint `{}'", device_filter)
+            bail!(DinghyError::DeviceNotFound(Some(device_filter.to_string())))
         }
         devices
             .into_iter()
@@ -255,7 +761,8 @@ fn select_platform_and_device_from_cli(
                     .platforms()
                     .iter()
                     .filter(|pf| !is_banned_auto_platform_id(&pf.id()))
-                    .find(|pf| pf.is_compatible_with(&**d))
+                    .filter(|pf| pf.is_compatible_with(&**d))
+                    .min_by_key(|pf| d.preferred_target() != Some(pf.rustc_triple()))
                     .cloned();
                 debug!(
                     "Looking for platform for {}: found {:?}",
@@ -54,13 +54,20 @@ pub fn build_env(name: &str) -> Result<String> {
     Ok(env::var(name)?)
 }
 
-/// Capitalize and replace `-` by `_`.
+/// Capitalize and replace anything but ASCII letters and digits by `_`, with
+/// a leading `_` if the name would start with a digit.
 pub fn envify<S: AsRef<str>>(name: S) -> String {
-    name.as_ref()
+    let envified: String = name
+        .as_ref()
         .chars()
         .map(|c| c.to_ascii_uppercase())
-        .map(|c| if c == '-' || c == '.' { '_' } else { c })
-        .collect()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if envified.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", envified)
+    } else {
+        envified
+    }
 }
 
 /// Set a bunch of environment variables.
@@ -155,3 +162,19 @@ fn target_key_from_triple<K: AsRef<OsStr>, R: AsRef<str>>(
     }
     target_key
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envify_makes_valid_env_var_names() {
+        assert_eq!(envify("armv7-linux-androideabi"), "ARMV7_LINUX_ANDROIDEABI");
+        assert_eq!(
+            envify("PKG_CONFIG_my.overlay v2_PREFIX"),
+            "PKG_CONFIG_MY_OVERLAY_V2_PREFIX"
+        );
+        assert_eq!(envify("3ds"), "_3DS");
+        assert_eq!(envify("é"), "_");
+    }
+}
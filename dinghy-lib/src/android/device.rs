@@ -1,26 +1,81 @@
+use crate::config::AndroidDeviceConfiguration;
+use crate::config::Compression;
+use crate::config::Launch;
+use crate::device::compress_bundle;
+use crate::device::criterion_dirs;
+use crate::device::fetch_trace;
+use crate::device::install_shared_test_data;
+use crate::device::lldb_gdbserver_ports;
+use crate::device::lldb_platform_command;
 use crate::device::make_remote_app;
+use crate::device::open_log_file;
+use crate::device::remote_run_command;
+use crate::device::remote_uncompress_check_command;
+use crate::device::remote_uncompress_command;
+use crate::device::run_and_tee;
+use crate::device::verify_test_data;
+use crate::device::wait_for_lldb_platform_port;
+use crate::device::SHARED_TEST_DATA_DIR;
 use crate::errors::*;
 use crate::platform::regular_platform::RegularPlatform;
 use crate::project::Project;
+use crate::utils::check_status;
+use crate::utils::command_line;
+use crate::utils::free_local_port;
 use crate::utils::path_to_str;
 use crate::Build;
 use crate::BuildBundle;
 use crate::Device;
+use crate::DeviceCheck;
 use crate::DeviceCompatibility;
+use crate::DeviceKind;
 use crate::Runnable;
-use std::io::Write;
-use std::{fmt, io, path, process};
+use cargo::core::compiler::CompileMode;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::{fmt, fs, path, process, time};
 
 static ANDROID_WORK_DIR: &str = "/data/local/tmp/dinghy";
+/// Where the bundle namespaces are made, the default one is the work dir.
+static ANDROID_BUNDLES_DIR: &str = "/data/local/tmp";
 
 pub struct AndroidDevice {
     pub adb: path::PathBuf,
     pub id: String,
+    pub name: String,
     pub supported_targets: Vec<&'static str>,
+    pub conf: AndroidDeviceConfiguration,
+    /// The lldb platform, its command line and the host ports forwarded to it.
+    lldb_server: Mutex<Option<(process::Child, String, Vec<u16>)>>,
+    installed_test_data: Mutex<HashSet<path::PathBuf>>,
 }
 
 impl AndroidDevice {
-    pub fn from_id(adb: path::PathBuf, id: &str) -> Result<AndroidDevice> {
+    /// A device with known parameters, without querying it: `adb` is only
+    /// run when the device is used.
+    pub fn new(
+        adb: path::PathBuf,
+        id: &str,
+        supported_targets: Vec<&'static str>,
+        conf: AndroidDeviceConfiguration,
+    ) -> AndroidDevice {
+        AndroidDevice {
+            adb,
+            id: id.into(),
+            name: id.into(),
+            supported_targets,
+            conf,
+            lldb_server: Mutex::new(None),
+            installed_test_data: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Queries the supported targets and the name of the connected device `id`.
+    pub fn from_id(
+        adb: path::PathBuf,
+        id: &str,
+        conf: AndroidDeviceConfiguration,
+    ) -> Result<AndroidDevice> {
         for prop in &[
             "ro.product.cpu.abilist",
             "ro.product.cpu.abi",
@@ -52,22 +107,142 @@ impl AndroidDevice {
                     })
                     .collect::<Vec<_>>();
 
+                let name = AndroidDevice::model_name(&adb, id);
                 return Ok(AndroidDevice {
-                    adb,
-                    id: id.into(),
-                    supported_targets: supported_targets,
+                    name,
+                    ..AndroidDevice::new(adb, id, supported_targets, conf)
                 });
             }
         }
         bail!("Could not match a platform to the device")
     }
 
+    /// "Manufacturer Model" of the device, or its serial id if unknown.
+    fn model_name(adb: &path::Path, id: &str) -> String {
+        let mut name = vec![];
+        for prop in ["ro.product.manufacturer", "ro.product.model"] {
+            let getprop_output = process::Command::new(adb)
+                .args(["-s", id, "shell", "getprop", prop])
+                .output();
+            match getprop_output {
+                Ok(output) if output.status.success() => {
+                    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                    if !value.is_empty() {
+                        name.push(value);
+                    }
+                }
+                other => {
+                    debug!(
+                        "Android device {}, getprop {} failed: {:?}",
+                        id, prop, other
+                    );
+                    return id.to_string();
+                }
+            }
+        }
+        if name.is_empty() {
+            id.to_string()
+        } else {
+            name.join(" ")
+        }
+    }
+
     fn adb(&self) -> Result<process::Command> {
         let mut command = process::Command::new(&self.adb);
         command.arg("-s").arg(&self.id);
         Ok(command)
     }
 
+    /// Explains why the shell of the device refused to execute an installed runnable.
+    fn exec_failure_hint(
+        &self,
+        project: &Project,
+        runnable_id: &str,
+        remote_bundle: &BuildBundle,
+        output: &str,
+    ) -> String {
+        let version = self
+            .adb()
+            .and_then(|mut adb| {
+                Ok(adb
+                    .args(["shell", "getprop", "ro.build.version.release"])
+                    .output()?)
+            })
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .ok()
+            .filter(|version| !version.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+        let device = format!("{} (Android {})", self.id, version);
+        if output.contains("Text file busy") {
+            return format!(
+                "{} could not be executed on {}: the executable is still open (text file busy), a previous run may still be running",
+                runnable_id, device
+            );
+        }
+        let noexec = self
+            .adb()
+            .and_then(|mut adb| Ok(adb.args(["shell", "cat", "/proc/mounts"]).output()?))
+            .ok()
+            .and_then(|mounts| {
+                mount_options(
+                    &String::from_utf8_lossy(&mounts.stdout),
+                    &remote_bundle.bundle_exe,
+                )
+                .map(|options| options.split(',').any(|option| option == "noexec"))
+            })
+            .unwrap_or(false);
+        let fallback = if project.conf.run_as_package.is_none() {
+            ", or set run_as_package to run from the data directory of a debuggable app"
+        } else {
+            ""
+        };
+        if noexec {
+            format!(
+                "{} could not be executed on {}: {} is on a noexec mount{}",
+                runnable_id,
+                device,
+                remote_bundle.bundle_dir.display(),
+                fallback
+            )
+        } else if self.conf.selinux_context.is_none() {
+            format!(
+                "{} could not be executed on {}. If SELinux is enforcing there, set a selinux_context for the device{}",
+                runnable_id, device, fallback
+            )
+        } else {
+            format!(
+                "{} could not be executed on {} (Permission denied){}",
+                runnable_id, device, fallback
+            )
+        }
+    }
+
+    fn remove_forwards(&self, ports: &[u16]) -> Result<()> {
+        for port in ports {
+            check_status(
+                self.adb()?
+                    .arg("forward")
+                    .arg("--remove")
+                    .arg(format!("tcp:{}", port)),
+            )
+            .with_context(|| format!("Error removing lldb port forward tcp:{}", port))?;
+        }
+        Ok(())
+    }
+
+    fn check_remote(&self, remote_command: &str) -> Result<()> {
+        // adb shell does not always forward the exit status, look at the output instead
+        let output = self
+            .adb()?
+            .arg("shell")
+            .arg(format!("{} && echo DINGHY_CHECK_OK", remote_command))
+            .output()?;
+        if !String::from_utf8_lossy(&output.stdout).contains("DINGHY_CHECK_OK") {
+            bail!("'{}' failed on {}", remote_command, self.id)
+        }
+        Ok(())
+    }
+
     fn install_app(
         &self,
         project: &Project,
@@ -75,52 +250,280 @@ impl AndroidDevice {
         runnable: &Runnable,
     ) -> Result<(BuildBundle, BuildBundle)> {
         info!("Install {} to {}", runnable.id, self.id);
-        if !self
+        if let Some(package) = &project.conf.run_as_package {
+            self.check_run_as(package)?;
+        }
+        let build_bundle = make_remote_app(project, build, runnable)?;
+        let remote_bundle = AndroidDevice::to_remote_bundle(&build_bundle)?;
+        check_status(
+            self.adb()?
+                .arg("shell")
+                .arg("mkdir")
+                .arg("-p")
+                .arg(&remote_bundle.root_dir),
+        )
+        .with_context(|| {
+            format!(
+                "Failure to create dinghy work dir '{:?}' on target android device",
+                remote_bundle.root_dir
+            )
+        })?;
+
+        install_shared_test_data(
+            &build_bundle,
+            &remote_bundle,
+            &self.installed_test_data,
+            |local, remote| {
+                let remote_parent = remote
+                    .parent()
+                    .ok_or_else(|| anyhow!("Invalid path {}", remote.display()))?;
+                self.check_remote(&format!("mkdir -p '{}'", path_to_str(remote_parent)?))?;
+                self.sync(local, remote_parent)
+            },
+        )?;
+
+        let compressed = match project.conf.transfer_compression {
+            Some(compression) => {
+                self.install_compressed(&build_bundle, &remote_bundle, compression)?
+            }
+            None => false,
+        };
+        if !compressed {
+            self.sync(
+                &build_bundle.bundle_dir,
+                &remote_bundle.bundle_dir.parent().ok_or_else(|| {
+                    anyhow!("Invalid path {}", remote_bundle.bundle_dir.display())
+                })?,
+            )?;
+            self.sync(
+                &build_bundle.lib_dir,
+                &remote_bundle
+                    .lib_dir
+                    .parent()
+                    .ok_or_else(|| anyhow!("Invalid path {}", remote_bundle.lib_dir.display()))?,
+            )?;
+        }
+
+        debug!("Chmod target exe {}", remote_bundle.bundle_exe.display());
+        check_status(
+            self.adb()?
+                .arg("shell")
+                .arg("chmod")
+                .arg("755")
+                .arg(&remote_bundle.bundle_exe),
+        )
+        .context("Failure in android install")?;
+        if let Some(context) = &self.conf.selinux_context {
+            debug!(
+                "Relabel target exe {} as {}",
+                remote_bundle.bundle_exe.display(),
+                context
+            );
+            self.check_remote(&format!(
+                "chcon {} '{}'",
+                ::shell_escape::escape(context.into()),
+                path_to_str(&remote_bundle.bundle_exe)?
+            ))
+            .with_context(|| format!("Couldn't set the SELinux context {}", context))?;
+        }
+        match &project.conf.run_as_package {
+            Some(package) => {
+                let app_bundle = self.install_run_as(package, &remote_bundle)?;
+                Ok((build_bundle, app_bundle))
+            }
+            None => Ok((build_bundle, remote_bundle)),
+        }
+    }
+
+    /// The adb command running the installed `remote_bundle`. Its last output
+    /// line carries the exit code, adb does not forward it.
+    pub fn run_command(
+        &self,
+        project: &Project,
+        build: &Build,
+        remote_bundle: &BuildBundle,
+        args: &[&str],
+        envs: &[&str],
+    ) -> Result<process::Command> {
+        let args: Vec<String> = args
+            .iter()
+            .map(|&a| ::shell_escape::escape(a.into()).to_string())
+            .collect();
+        let exec_prefix = match AndroidDevice::app_process_launcher(project)? {
+            Some(launcher) => Some(match &self.conf.exec_prefix {
+                Some(exec_prefix) => format!("{} {}", launcher, exec_prefix),
+                None => launcher,
+            }),
+            None => self.conf.exec_prefix.clone(),
+        };
+        let mut command = remote_run_command(
+            project,
+            build,
+            remote_bundle,
+            &[],
+            exec_prefix.as_deref(),
+            envs,
+            &args,
+        )?;
+        if let Some(package) = &project.conf.run_as_package {
+            command = AndroidDevice::run_as_command(package, &command);
+        }
+        let mut adb = self.adb()?;
+        adb.arg("shell").arg(format!(
+            "{} ; echo FORWARD_RESULT_TO_DINGHY_BECAUSE_ADB_DOES_NOT=$?",
+            command
+        ));
+        Ok(adb)
+    }
+
+    /// The `app_process` command starting the configured main class, which
+    /// gets the executable and the arguments, when runnables are launched
+    /// through it.
+    fn app_process_launcher(project: &Project) -> Result<Option<String>> {
+        if project.conf.launch != Some(Launch::AppProcess) {
+            return Ok(None);
+        }
+        let class_path = project
+            .conf
+            .app_process_class_path
+            .as_ref()
+            .ok_or_else(|| anyhow!("launch = \"app_process\" needs an app_process_class_path"))?;
+        let main_class = project
+            .conf
+            .app_process_main_class
+            .as_ref()
+            .ok_or_else(|| anyhow!("launch = \"app_process\" needs an app_process_main_class"))?;
+        Ok(Some(format!(
+            "app_process -Djava.class.path={} /system/bin {}",
+            ::shell_escape::escape(class_path.into()),
+            main_class
+        )))
+    }
+
+    fn run_as_command(package: &str, command: &str) -> String {
+        format!(
+            "run-as {} sh -c {}",
+            package,
+            ::shell_escape::escape(command.into())
+        )
+    }
+
+    fn check_run_as(&self, package: &str) -> Result<()> {
+        let output = self
             .adb()?
             .arg("shell")
-            .arg("mkdir")
-            .arg("-p")
-            .arg(ANDROID_WORK_DIR)
-            .status()?
-            .success()
-        {
+            .arg(format!(
+                "{} && echo DINGHY_RUN_AS_OK",
+                AndroidDevice::run_as_command(package, "true")
+            ))
+            .output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.contains("DINGHY_RUN_AS_OK") {
             bail!(
-                "Failure to create dinghy work dir '{:?}' on target android device",
-                ANDROID_WORK_DIR
+                "Can not run as {} on {}, is the package installed and debuggable? {}",
+                package,
+                self.id,
+                stdout.trim()
             )
         }
+        Ok(())
+    }
 
-        let build_bundle = make_remote_app(project, build, runnable)?;
-        let remote_bundle = AndroidDevice::to_remote_bundle(&build_bundle)?;
+    /// Copies the pushed bundle in the data directory of `package`, where
+    /// `run_app` starts it.
+    fn install_run_as(&self, package: &str, remote_bundle: &BuildBundle) -> Result<BuildBundle> {
+        let app_bundle = remote_bundle.relocate_to(format!("/data/data/{}", package))?;
+        let shared_test_data = match remote_bundle.test_data_dir {
+            Some(_) => format!(
+                " '{}'",
+                path_to_str(&remote_bundle.root_dir.join(SHARED_TEST_DATA_DIR))?
+            ),
+            None => String::new(),
+        };
+        let command = format!(
+            "mkdir -p '{}' && cp -r '{}' '{}'{} '{}/' && chmod 755 '{}'",
+            path_to_str(&app_bundle.root_dir)?,
+            path_to_str(&remote_bundle.bundle_dir)?,
+            path_to_str(&remote_bundle.lib_dir)?,
+            shared_test_data,
+            path_to_str(&app_bundle.root_dir)?,
+            path_to_str(&app_bundle.bundle_exe)?,
+        );
+        debug!("Copy {} to the {} data dir", remote_bundle.id, package);
+        let output = self
+            .adb()?
+            .arg("shell")
+            .arg(format!(
+                "{} && echo DINGHY_RUN_AS_OK",
+                AndroidDevice::run_as_command(package, &command)
+            ))
+            .output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.contains("DINGHY_RUN_AS_OK") {
+            bail!(
+                "Failure copying {} to the {} data dir: {}",
+                remote_bundle.id,
+                package,
+                stdout.trim()
+            )
+        }
+        Ok(app_bundle)
+    }
 
-        self.sync(
-            &build_bundle.bundle_dir,
-            &remote_bundle
-                .bundle_dir
-                .parent()
-                .ok_or_else(|| anyhow!("Invalid path {}", remote_bundle.bundle_dir.display()))?,
-        )?;
-        self.sync(
-            &build_bundle.lib_dir,
-            &remote_bundle
-                .lib_dir
-                .parent()
-                .ok_or_else(|| anyhow!("Invalid path {}", remote_bundle.lib_dir.display()))?,
-        )?;
+    fn install_compressed(
+        &self,
+        build_bundle: &BuildBundle,
+        remote_bundle: &BuildBundle,
+        compression: Compression,
+    ) -> Result<bool> {
+        // adb shell does not always forward the exit status, look at the output instead
+        let output = self
+            .adb()?
+            .arg("shell")
+            .arg(format!(
+                "{} && echo DINGHY_UNCOMPRESS_OK",
+                remote_uncompress_check_command(compression)
+            ))
+            .output()?;
+        if !String::from_utf8_lossy(&output.stdout).contains("DINGHY_UNCOMPRESS_OK") {
+            warn!(
+                "tar or {} missing on {}, falling back to uncompressed transfer",
+                compression.program(),
+                self.id
+            );
+            return Ok(false);
+        }
 
-        debug!("Chmod target exe {}", remote_bundle.bundle_exe.display());
-        if !self
+        let archive = compress_bundle(build_bundle, compression)?;
+        let remote_archive = remote_bundle.root_dir.join(
+            archive
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid archive path {}", archive.display()))?,
+        );
+        let mut command = self.adb()?;
+        command.arg("push").arg(&archive).arg(&remote_archive);
+        if !log_enabled!(::log::Level::Debug) {
+            command.stdout(::std::process::Stdio::null());
+            command.stderr(::std::process::Stdio::null());
+        }
+        debug!("Running {:?}", command);
+        check_status(&mut command).context("Error pushing archive to android device")?;
+
+        let output = self
             .adb()?
             .arg("shell")
-            .arg("chmod")
-            .arg("755")
-            .arg(&remote_bundle.bundle_exe)
-            .status()?
-            .success()
-        {
-            bail!("Failure in android install");
+            .arg(format!(
+                "{} && echo DINGHY_UNCOMPRESS_OK",
+                remote_uncompress_command(remote_bundle, &remote_archive, compression)?
+            ))
+            .output()?;
+        if !String::from_utf8_lossy(&output.stdout).contains("DINGHY_UNCOMPRESS_OK") {
+            bail!(
+                "Error extracting archive on android device: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
         }
-        Ok((build_bundle, remote_bundle))
+        Ok(true)
     }
 
     fn sync<FP: AsRef<path::Path>, TP: AsRef<path::Path>>(
@@ -143,15 +546,41 @@ impl AndroidDevice {
             command.stderr(::std::process::Stdio::null());
         }
         debug!("Running {:?}", command);
-        if !command.status()?.success() {
-            bail!("Error syncing android directory ({:?})", command)
-        } else {
-            Ok(())
+        check_status(&mut command).context("Error syncing android directory")
+    }
+
+    /// Copies back `from_path` from the device, if it exists there.
+    fn fetch<FP: AsRef<path::Path>, TP: AsRef<path::Path>>(
+        &self,
+        from_path: FP,
+        to_path: TP,
+    ) -> Result<()> {
+        let from_path = path_to_str(from_path.as_ref())?;
+        if self
+            .check_remote(&format!("test -d '{}'", from_path))
+            .is_err()
+        {
+            debug!("Nothing to fetch at {}", from_path);
+            return Ok(());
         }
+        let to_path = to_path.as_ref();
+        let to_parent = to_path
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid path {}", to_path.display()))?;
+        fs::create_dir_all(to_parent)?;
+        // adb pull puts the directory in the destination one
+        let mut command = self.adb()?;
+        command.arg("pull").arg(from_path).arg(to_parent);
+        if !log_enabled!(::log::Level::Debug) {
+            command.stdout(::std::process::Stdio::null());
+        }
+        debug!("Running {:?}", command);
+        check_status(&mut command).context("Error fetching from android device")?;
+        Ok(())
     }
 
     fn to_remote_bundle(build_bundle: &BuildBundle) -> Result<BuildBundle> {
-        build_bundle.replace_prefix_with(ANDROID_WORK_DIR)
+        build_bundle.relocate_to(ANDROID_BUNDLES_DIR)
     }
 }
 
@@ -170,31 +599,53 @@ impl DeviceCompatibility for AndroidDevice {
 }
 
 impl Device for AndroidDevice {
+    fn check(&self) -> Vec<DeviceCheck> {
+        let reachable = self.check_remote("true");
+        if reachable.is_err() {
+            return vec![DeviceCheck::new("device reachable", true, reachable)];
+        }
+        vec![
+            DeviceCheck::new("device reachable", true, reachable),
+            DeviceCheck::new(
+                "work dir writable",
+                true,
+                self.check_remote(&format!(
+                    "mkdir -p {} && test -w {}",
+                    ANDROID_WORK_DIR, ANDROID_WORK_DIR
+                )),
+            ),
+            DeviceCheck::new(
+                "tar available (compressed transfer)",
+                false,
+                self.check_remote("command -v tar"),
+            ),
+            DeviceCheck::new(
+                "lldb-server available (lldbproxy)",
+                false,
+                self.check_remote(&format!("test -x {}/lldb-server", ANDROID_WORK_DIR)),
+            ),
+        ]
+    }
+
     fn clean_app(&self, build_bundle: &BuildBundle) -> Result<()> {
         let remote_bundle = AndroidDevice::to_remote_bundle(build_bundle)?;
         debug!("Cleaup device");
-        if !self
-            .adb()?
-            .arg("shell")
-            .arg("rm")
-            .arg("-rf")
-            .arg(&remote_bundle.bundle_dir)
-            .status()?
-            .success()
-        {
-            bail!("Failure in android clean")
-        }
-        if !self
-            .adb()?
-            .arg("shell")
-            .arg("rm")
-            .arg("-rf")
-            .arg(&remote_bundle.lib_dir)
-            .status()?
-            .success()
-        {
-            bail!("Failure in android clean")
-        }
+        check_status(
+            self.adb()?
+                .arg("shell")
+                .arg("rm")
+                .arg("-rf")
+                .arg(&remote_bundle.bundle_dir),
+        )
+        .context("Failure in android clean")?;
+        check_status(
+            self.adb()?
+                .arg("shell")
+                .arg("rm")
+                .arg("-rf")
+                .arg(&remote_bundle.lib_dir),
+        )
+        .context("Failure in android clean")?;
         Ok(())
     }
 
@@ -212,8 +663,63 @@ impl Device for AndroidDevice {
         &self.id
     }
 
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Android
+    }
+
     fn name(&self) -> &str {
-        "android device"
+        &self.name
+    }
+
+    fn preferred_target(&self) -> Option<&str> {
+        self.conf.preferred_target.as_deref()
+    }
+
+    fn pull(&self, remote: &str, local: &path::Path) -> Result<()> {
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // adb hands the paths to the device as they are, there is no remote
+        // shell to quote them for
+        let mut command = self.adb()?;
+        command.arg("pull").arg(remote).arg(local);
+        if !log_enabled!(::log::Level::Debug) {
+            command.stdout(::std::process::Stdio::null());
+        }
+        debug!("Running {:?}", command);
+        check_status(&mut command)
+            .with_context(|| format!("Error pulling {} from {}", remote, self.id))
+    }
+
+    fn push(&self, local: &path::Path, remote: &str) -> Result<()> {
+        // adb push creates the missing parent directories, and like pull
+        // takes the paths as they are
+        let mut command = self.adb()?;
+        command.arg("push").arg(local).arg(remote);
+        if !log_enabled!(::log::Level::Debug) {
+            command.stdout(::std::process::Stdio::null());
+        }
+        debug!("Running {:?}", command);
+        check_status(&mut command)
+            .with_context(|| format!("Error pushing {} to {}", local.display(), self.id))
+    }
+
+    fn install_runnables(
+        &self,
+        project: &Project,
+        build: &Build,
+        args: &[&str],
+        envs: &[&str],
+    ) -> Result<Vec<(BuildBundle, String)>> {
+        let mut installed = vec![];
+        for runnable in &build.runnables {
+            let (_, remote_bundle) = self
+                .install_app(project, build, runnable)
+                .map_err(|e| e.context(DinghyError::InstallFailed(self.id.clone())))?;
+            let command = self.run_command(project, build, &remote_bundle, args, envs)?;
+            installed.push((remote_bundle, command_line(&command)));
+        }
+        Ok(installed)
     }
 
     fn run_app(
@@ -222,65 +728,173 @@ impl Device for AndroidDevice {
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>> {
+    ) -> Result<(Vec<BuildBundle>, time::Duration)> {
+        if let Some(tool) = &build.build_args.trace {
+            self.check_remote(&format!("command -v {}", tool))
+                .with_context(|| format!("{} is not installed on {}", tool, self.id))?;
+        }
         let mut build_bundles = vec![];
-        let args: Vec<String> = args
-            .iter()
-            .map(|&a| ::shell_escape::escape(a.into()).to_string())
-            .collect();
+        let mut install_time = time::Duration::ZERO;
         for runnable in &build.runnables {
-            let (build_bundle, remote_bundle) = self.install_app(&project, &build, &runnable)?;
-            let command = format!(
-                "cd '{}'; {} DINGHY=1 RUST_BACKTRACE=1 LD_LIBRARY_PATH=\"{}:$LD_LIBRARY_PATH\" {} {} {} ; echo FORWARD_RESULT_TO_DINGHY_BECAUSE_ADB_DOES_NOT=$?",
-                path_to_str(&remote_bundle.bundle_dir)?,
-                envs.join(" "),
-                path_to_str(&remote_bundle.lib_dir)?,
-                path_to_str(&remote_bundle.bundle_exe)?,
-                if build.build_args.compile_mode == ::cargo::core::compiler::CompileMode::Bench { "--bench" } else { "" },
-                args.join(" "));
+            let install_start = time::Instant::now();
+            let (build_bundle, remote_bundle) = self
+                .install_app(&project, &build, &runnable)
+                .map_err(|e| e.context(DinghyError::InstallFailed(self.id.clone())))?;
+            install_time += install_start.elapsed();
+            verify_test_data(
+                project,
+                build,
+                runnable,
+                &build_bundle,
+                &remote_bundle,
+                &self.id,
+                |command| {
+                    let output = self.adb()?.arg("shell").arg(command).output()?;
+                    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+                },
+            )?;
+            let mut command = self.run_command(project, build, &remote_bundle, args, envs)?;
             info!(
                 "Run {} on {} ({:?})",
                 runnable.id, self.id, build.build_args.compile_mode
             );
 
-            if !self
-                .adb()?
-                .arg("shell")
-                .arg(&command)
-                .output()
+            let log = open_log_file(build, &self.id, runnable)?;
+            let output = run_and_tee(&mut command, log)
                 .with_context(|| format!("Couldn't run {} using adb.", runnable.exe.display()))
-                .and_then(|output| {
-                    if output.status.success() {
-                        let _ = io::stdout().write(output.stdout.as_slice());
-                        let _ = io::stderr().write(output.stderr.as_slice());
-                        String::from_utf8(output.stdout).with_context(|| {
+                .and_then(|(status, stdout)| {
+                    if status.success() {
+                        String::from_utf8(stdout).with_context(|| {
                             format!("Couldn't run {} using adb.", runnable.exe.display())
                         })
                     } else {
-                        bail!("Couldn't run {} using adb.", runnable.exe.display())
+                        bail!(DinghyError::CommandFailed {
+                            command: command_line(&command),
+                            code: status.code(),
+                        })
                     }
-                })
-                .map(|output| output.lines().last().unwrap_or("").to_string())
-                .map(|last_line| {
-                    last_line.contains("FORWARD_RESULT_TO_DINGHY_BECAUSE_ADB_DOES_NOT=0")
-                })?
-            {
-                bail!("Test failed 🐛")
+                })?;
+            let code = output
+                .lines()
+                .last()
+                .unwrap_or("")
+                .split("FORWARD_RESULT_TO_DINGHY_BECAUSE_ADB_DOES_NOT=")
+                .nth(1)
+                .and_then(|code| code.trim().parse::<i32>().ok());
+            if code == Some(126) {
+                // The shell found the executable but was not allowed to run it
+                warn!(
+                    "{}",
+                    self.exec_failure_hint(project, &runnable.id, &remote_bundle, &output)
+                );
+            }
+            if let Some(tool) = &build.build_args.trace {
+                fetch_trace(self, tool, runnable, &remote_bundle);
+            }
+            if code != Some(0) {
+                bail!(DinghyError::RemoteCommandFailed { code })
+            }
+            if build.build_args.compile_mode == CompileMode::Bench {
+                let (remote_criterion, local_criterion) = criterion_dirs(project, &remote_bundle)?;
+                self.fetch(&remote_criterion, &local_criterion)?;
             }
 
             build_bundles.push(build_bundle);
         }
-        Ok(build_bundles)
+        Ok((build_bundles, install_time))
     }
 
-    fn start_remote_lldb(&self) -> Result<String> {
-        unimplemented!()
+    fn start_remote_lldb(&self, local_port: Option<u16>) -> Result<String> {
+        let port = match local_port {
+            Some(port) => port,
+            None => free_local_port()?,
+        };
+        let lldb_server = format!("{}/lldb-server", ANDROID_WORK_DIR);
+        if !self
+            .adb()?
+            .arg("shell")
+            .arg("test")
+            .arg("-x")
+            .arg(&lldb_server)
+            .status()?
+            .success()
+        {
+            bail!(
+                "No lldb-server found on device at '{}', push the one from your NDK there first",
+                lldb_server
+            )
+        }
+        let gdbserver_ports = lldb_gdbserver_ports(port)?;
+        let port_file = format!("{}/dinghy-lldb-server.port", ANDROID_WORK_DIR);
+        let platform = lldb_platform_command(&lldb_server, &port_file, &gdbserver_ports);
+        let mut command = self.adb()?;
+        command
+            .arg("shell")
+            .arg(format!("rm -f {} && exec {}", port_file, platform));
+        debug!("Running {:?}", command);
+        let mut server = command
+            .spawn()
+            .with_context(|| format!("Couldn't run {:?}", command))?;
+        let forward = |local: u16, remote: u16| -> Result<()> {
+            let mut command = self.adb()?;
+            command
+                .arg("forward")
+                .arg(format!("tcp:{}", local))
+                .arg(format!("tcp:{}", remote));
+            debug!("Running {:?}", command);
+            check_status(&mut command).context("Error forwarding lldb port")
+        };
+        // The gdbservers keep their port number, lldb gets it from the platform
+        let forwarded = wait_for_lldb_platform_port(|| {
+            let output = self
+                .adb()?
+                .arg("shell")
+                .arg("cat")
+                .arg(&port_file)
+                .output()?;
+            Ok(String::from_utf8(output.stdout)?)
+        })
+        .and_then(|remote_port| forward(port, remote_port))
+        .and_then(|_| gdbserver_ports.clone().try_for_each(|it| forward(it, it)));
+        let forwarded_ports = std::iter::once(port)
+            .chain(gdbserver_ports)
+            .collect::<Vec<_>>();
+        if let Err(e) = forwarded {
+            let _ = server.kill();
+            let _ = self.remove_forwards(&forwarded_ports);
+            return Err(e);
+        }
+        *self
+            .lldb_server
+            .lock()
+            .map_err(|_| anyhow!("poisoned lock"))? = Some((server, platform, forwarded_ports));
+        Ok(format!("localhost:{}", port))
+    }
+
+    fn stop_remote_lldb(&self) -> Result<()> {
+        let server = self
+            .lldb_server
+            .lock()
+            .map_err(|_| anyhow!("poisoned lock"))?
+            .take();
+        if let Some((mut server, platform, forwarded_ports)) = server {
+            debug!("Stopping lldb-server on {}", self.id);
+            let _ = self
+                .adb()?
+                .arg("shell")
+                .arg(format!("pkill -f '{}'", platform))
+                .status();
+            let _ = server.kill();
+            server.wait()?;
+            self.remove_forwards(&forwarded_ports)?;
+        }
+        Ok(())
     }
 }
 
 impl fmt::Display for AndroidDevice {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "Android/{}", self.id)
+        write!(fmt, "Android/{} ({})", self.id, self.name)
     }
 }
 
@@ -288,10 +902,49 @@ impl fmt::Debug for AndroidDevice {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         Ok(fmt.write_str(
             format!(
-                "Android {{ \"id\": \"{}\", \"supported_targets\": {:?} }}",
-                self.id, self.supported_targets
+                "Android {{ \"id\": \"{}\", \"name\": \"{}\", \"supported_targets\": {:?} }}",
+                self.id, self.name, self.supported_targets
             )
             .as_str(),
         )?)
     }
 }
+
+/// Options of the mount holding `path`, from the content of `/proc/mounts`.
+fn mount_options<'a>(mounts: &'a str, path: &path::Path) -> Option<&'a str> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?;
+            let options = fields.nth(1)?;
+            Some((mount_point, options))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.len())
+        .map(|(_, options)| options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_options_of_the_deepest_mount() {
+        let mounts = "/dev/root / ext4 ro,seclabel 0 0\n\
+                      /dev/block/dm-5 /data f2fs rw,nosuid,nodev,noatime 0 0\n\
+                      tmpfs /data/local/tmp tmpfs rw,nosuid,nodev,noexec 0 0\n";
+        assert_eq!(
+            mount_options(mounts, path::Path::new("/data/local/tmp/dinghy/test")),
+            Some("rw,nosuid,nodev,noexec")
+        );
+        assert_eq!(
+            mount_options(mounts, path::Path::new("/data/data/org.app/dinghy")),
+            Some("rw,nosuid,nodev,noatime")
+        );
+        assert_eq!(
+            mount_options(mounts, path::Path::new("/system/bin")),
+            Some("ro,seclabel")
+        );
+    }
+}
@@ -1,8 +1,9 @@
+use crate::config::AndroidDeviceConfiguration;
 use crate::config::PlatformConfiguration;
 use crate::platform::regular_platform::RegularPlatform;
 use crate::toolchain::ToolchainConfig;
-use crate::{Compiler, Device, Platform, PlatformManager, Result};
-use std::{env, fs, path, process, sync};
+use crate::{Compiler, Configuration, Device, Platform, PlatformManager, Result};
+use std::{collections, env, fs, path, process, sync, thread, time};
 
 pub use self::device::AndroidDevice;
 
@@ -10,30 +11,24 @@ use anyhow::Context;
 
 mod device;
 
+const UNAUTHORIZED_RETRIES: usize = 3;
+
 pub struct AndroidManager {
     compiler: sync::Arc<Compiler>,
     adb: path::PathBuf,
+    ndk_version: Option<String>,
+    device_confs: collections::BTreeMap<String, AndroidDeviceConfiguration>,
 }
 
 impl PlatformManager for AndroidManager {
     fn devices(&self) -> Result<Vec<Box<dyn Device>>> {
-        let result = process::Command::new(&self.adb).arg("devices").output()?;
-        let mut devices = vec![];
-        let device_regex = ::regex::Regex::new(r#"^(\S+)\tdevice\r?$"#)?;
-        for line in String::from_utf8(result.stdout)?.split("\n").skip(1) {
-            if let Some(caps) = device_regex.captures(line) {
-                let d = AndroidDevice::from_id(self.adb.clone(), &caps[1])?;
-                debug!(
-                    "Discovered Android device {} ({:?})",
-                    d, d.supported_targets
-                );
-                devices.push(Box::new(d) as Box<dyn Device>);
-            }
-        }
-        Ok(devices)
+        Ok(discover_devices(&self.adb, &self.device_confs)?
+            .into_iter()
+            .map(|d| Box::new(d) as Box<dyn Device>)
+            .collect())
     }
     fn platforms(&self) -> Result<Vec<Box<dyn Platform>>> {
-        if let Some(ndk) = ndk()? {
+        if let Some(ndk) = ndk(self.ndk_version.as_deref())? {
             let default_api_level = "21";
             debug!("Android NDK: {:?}", ndk);
             let version = ndk_version(&ndk)?;
@@ -127,11 +122,20 @@ impl PlatformManager for AndroidManager {
 }
 
 impl AndroidManager {
-    pub fn probe(compiler: sync::Arc<Compiler>) -> Option<AndroidManager> {
-        match adb() {
+    pub fn probe(compiler: sync::Arc<Compiler>, conf: &Configuration) -> Option<AndroidManager> {
+        match adb(conf) {
             Ok(adb) => {
                 debug!("ADB found: {:?}", adb);
-                Some(AndroidManager { adb, compiler })
+                Some(AndroidManager {
+                    adb,
+                    ndk_version: conf.android_ndk_version.clone(),
+                    compiler,
+                    device_confs: conf.android_devices.clone(),
+                })
+            }
+            Err(e) if adb_override(conf).is_some() => {
+                warn!("{}, android disabled", e);
+                None
             }
             Err(_) => {
                 debug!("adb not found in path, android disabled");
@@ -141,6 +145,56 @@ impl AndroidManager {
     }
 }
 
+fn discover_devices(
+    adb: &path::Path,
+    device_confs: &collections::BTreeMap<String, AndroidDeviceConfiguration>,
+) -> Result<Vec<AndroidDevice>> {
+    let mut states = device_states(adb)?;
+    // Give some time to accept a USB debugging prompt that just showed up
+    for _ in 0..UNAUTHORIZED_RETRIES {
+        if !states.iter().any(|(_, state)| state == "unauthorized") {
+            break;
+        }
+        debug!("Waiting for unauthorized Android devices");
+        thread::sleep(time::Duration::from_secs(1));
+        states = device_states(adb)?;
+    }
+
+    let mut devices = vec![];
+    for (id, state) in states {
+        match state.as_str() {
+            "device" => {
+                let conf = device_confs.get(&id).cloned().unwrap_or_default();
+                let d = AndroidDevice::from_id(adb.to_path_buf(), &id, conf)?;
+                debug!(
+                    "Discovered Android device {} ({:?})",
+                    d, d.supported_targets
+                );
+                devices.push(d);
+            }
+            "unauthorized" => warn!(
+                "Android device {} is unauthorized, accept the USB debugging prompt on the device",
+                id
+            ),
+            state => warn!("Android device {} is {}, ignoring it", id, state),
+        }
+    }
+    Ok(devices)
+}
+
+/// Serial ids and states ("device", "unauthorized", "offline"...) listed by
+/// `adb devices`.
+fn device_states(adb: &path::Path) -> Result<Vec<(String, String)>> {
+    let result = process::Command::new(adb).arg("devices").output()?;
+    let device_regex = ::regex::Regex::new(r#"^(\S+)\t(\S+)\r?$"#)?;
+    Ok(String::from_utf8(result.stdout)?
+        .split("\n")
+        .skip(1)
+        .filter_map(|line| device_regex.captures(line))
+        .map(|caps| (caps[1].to_string(), caps[2].to_string()))
+        .collect())
+}
+
 fn probable_sdk_locs() -> Result<Vec<path::PathBuf>> {
     let mut v = vec![];
     for var in &[
@@ -175,7 +229,19 @@ fn probable_sdk_locs() -> Result<Vec<path::PathBuf>> {
     Ok(v)
 }
 
-fn ndk() -> Result<Option<path::PathBuf>> {
+/// The NDK to build with: the `version` pinned in the configuration, or else
+/// the one found in the environment or the SDKs.
+fn ndk(version: Option<&str>) -> Result<Option<path::PathBuf>> {
+    if let Some(version) = version {
+        // Only the Android platforms need it, do not fail the other ones
+        return match pinned_ndk(&probable_sdk_locs()?, version) {
+            Ok(ndk) => Ok(Some(ndk)),
+            Err(e) => {
+                warn!("{}, the Android platforms are not available", e);
+                Ok(None)
+            }
+        };
+    }
     if let Ok(path) = env::var("ANDROID_NDK_HOME") {
         return Ok(Some(path.into()));
     }
@@ -191,6 +257,35 @@ fn ndk() -> Result<Option<path::PathBuf>> {
     Ok(None)
 }
 
+/// The NDK `version` installed under `ndk/` in one of the `sdks`.
+fn pinned_ndk(sdks: &[path::PathBuf], version: &str) -> Result<path::PathBuf> {
+    if let Some(ndk) = sdks
+        .iter()
+        .map(|sdk| sdk.join("ndk").join(version))
+        .find(|ndk| ndk.is_dir())
+    {
+        return Ok(ndk);
+    }
+    let mut available = vec![];
+    for sdk in sdks {
+        if let Ok(entries) = sdk.join("ndk").read_dir() {
+            for entry in entries {
+                available.push(entry?.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    available.sort();
+    bail!(
+        "Android NDK {} is not installed, available versions: {}",
+        version,
+        if available.is_empty() {
+            "none".to_string()
+        } else {
+            available.join(", ")
+        }
+    )
+}
+
 fn ndk_version(ndk: &path::Path) -> Result<String> {
     let sources_prop_file = ndk.join("source.properties");
     let props = fs::read_to_string(&sources_prop_file).with_context(|| {
@@ -211,7 +306,17 @@ fn ndk_version(ndk: &path::Path) -> Result<String> {
     Ok(revision_line.split(" ").last().unwrap().to_string())
 }
 
-fn adb() -> Result<path::PathBuf> {
+/// adb binary explicitly chosen with `ANDROID_ADB` (or `DINGHY_ANDROID_ADB`)
+/// or `adb_path` in the configuration.
+fn adb_override(conf: &Configuration) -> Option<path::PathBuf> {
+    env::var("ANDROID_ADB")
+        .or_else(|_| env::var("DINGHY_ANDROID_ADB"))
+        .ok()
+        .or_else(|| conf.adb_path.clone())
+        .map(path::PathBuf::from)
+}
+
+fn adb(conf: &Configuration) -> Result<path::PathBuf> {
     fn try_out(command: &path::Path) -> bool {
         match process::Command::new(command)
             .arg("--version")
@@ -223,8 +328,11 @@ fn adb() -> Result<path::PathBuf> {
             Err(_) => false,
         }
     }
-    if let Ok(adb) = env::var("DINGHY_ANDROID_ADB") {
-        return Ok(adb.into());
+    if let Some(adb) = adb_override(conf) {
+        if !try_out(&adb) {
+            bail!("Configured adb {} does not run", adb.display())
+        }
+        return Ok(adb);
     }
     if let Ok(adb) = ::which::which("adb") {
         return Ok(adb);
@@ -271,6 +379,9 @@ fn find_non_legacy_ndk(sdk: &path::Path) -> Result<Option<path::PathBuf>> {
 mod tests {
 
     use super::*;
+    use crate::project::Project;
+    use crate::test_build;
+    use crate::BuildBundle;
 
     #[test]
     fn test_find_non_legacy_ndk() {
@@ -289,6 +400,93 @@ mod tests {
         assert_eq!(Some(expected), ndk);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_device_from_parameters_runs_adb_on_use() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let adb = dir.path().join("adb");
+        let log = dir.path().join("adb.log");
+        fs::write(
+            &adb,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\necho DINGHY_CHECK_OK\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&adb, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let device = AndroidDevice::new(
+            adb,
+            "SERIAL",
+            vec!["aarch64-linux-android"],
+            AndroidDeviceConfiguration::default(),
+        );
+        assert!(!log.exists());
+        assert_eq!(device.id(), "SERIAL");
+        assert!(device.check().iter().all(|check| check.result.is_ok()));
+        let log = fs::read_to_string(&log).unwrap();
+        assert!(
+            log.starts_with("-s SERIAL shell true && echo DINGHY_CHECK_OK\n"),
+            "{}",
+            log
+        );
+    }
+
+    #[test]
+    fn test_run_command_forwards_the_exit_code() {
+        let device = AndroidDevice::new(
+            path::PathBuf::from("adb"),
+            "SERIAL",
+            vec!["aarch64-linux-android"],
+            AndroidDeviceConfiguration::default(),
+        );
+        let project = Project::new(&sync::Arc::new(Configuration::default()));
+        let build = test_build(vec![]);
+        let remote_bundle = BuildBundle {
+            id: "my_test".to_string(),
+            bundle_dir: path::PathBuf::from("/data/local/tmp/dinghy/my_test"),
+            bundle_exe: path::PathBuf::from("/data/local/tmp/dinghy/my_test/_dinghy_my_test"),
+            lib_dir: path::PathBuf::from("/data/local/tmp/dinghy/overlay"),
+            root_dir: path::PathBuf::from("/data/local/tmp/dinghy"),
+            test_data_dir: None,
+        };
+
+        let command = device
+            .run_command(&project, &build, &remote_bundle, &["--exact", "a b"], &[])
+            .unwrap();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(command.get_program(), "adb");
+        assert_eq!(args[..3], ["-s", "SERIAL", "shell"]);
+        assert_eq!(args.len(), 4);
+        assert!(args[3].starts_with("cd '/data/local/tmp/dinghy/my_test' ;"));
+        assert!(args[3].ends_with(
+            "/data/local/tmp/dinghy/my_test/_dinghy_my_test  --exact 'a b' ; echo FORWARD_RESULT_TO_DINGHY_BECAUSE_ADB_DOES_NOT=$?"
+        ), "{}", args[3]);
+    }
+
+    #[test]
+    fn test_pinned_ndk() {
+        let sdk_dir = tempfile::tempdir().unwrap();
+        let sdk = sdk_dir.path().to_path_buf();
+        for version in &["21.3.6528147", "25.2.9519653"] {
+            fs::create_dir_all(sdk.join("ndk").join(version)).unwrap();
+        }
+
+        let ndk = pinned_ndk(std::slice::from_ref(&sdk), "21.3.6528147").unwrap();
+        assert_eq!(sdk.join("ndk/21.3.6528147"), ndk);
+
+        let error = pinned_ndk(&[sdk], "26.1.10909125").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Android NDK 26.1.10909125 is not installed, available versions: 21.3.6528147, 25.2.9519653"
+        );
+    }
+
     #[test]
     fn test_find_non_legacy_ndk_on_non_existing_directory() {
         let sdk = tempfile::tempdir().unwrap();
@@ -1,5 +1,6 @@
 extern crate cargo;
 
+use crate::errors::DinghyError;
 use crate::utils::arg_as_string_vec;
 use crate::utils::copy_and_sync_file;
 use crate::utils::is_library;
@@ -11,6 +12,8 @@ use cargo::core::compiler as CargoCoreCompiler;
 use cargo::core::compiler::Compilation;
 pub use cargo::core::compiler::CompileMode;
 use cargo::core::compiler::MessageFormat;
+use cargo::core::features::maybe_allow_nightly_features;
+use cargo::core::Package;
 use cargo::core::Workspace;
 use cargo::ops;
 use cargo::ops::CleanOptions;
@@ -22,6 +25,7 @@ use cargo::util::config::Config;
 use cargo::util::important_paths::find_root_manifest_for_wd;
 use cargo::util::interning::InternedString;
 use clap::ArgMatches;
+use dinghy_build::build_env::set_env;
 use dinghy_build::build_env::target_env_from_triple;
 use itertools::Itertools;
 use std::collections::HashSet;
@@ -45,9 +49,9 @@ use anyhow::Context;
 use crate::Platform;
 
 pub struct Compiler {
-    build_command: Box<dyn Fn(&dyn Platform, &BuildArgs) -> Result<Build>>,
-    clean_command: Box<dyn Fn(&dyn Platform) -> Result<()>>,
-    run_command: Box<dyn Fn(&dyn Platform, &BuildArgs, &[&str]) -> Result<()>>,
+    build_command: Box<dyn Fn(&dyn Platform, &BuildArgs) -> Result<Build> + Send + Sync>,
+    clean_command: Box<dyn Fn(&dyn Platform) -> Result<()> + Send + Sync>,
+    run_command: Box<dyn Fn(&dyn Platform, &BuildArgs, &[&str]) -> Result<()> + Send + Sync>,
 }
 
 impl Compiler {
@@ -60,7 +64,26 @@ impl Compiler {
     }
 
     pub fn build(&self, platform: &dyn Platform, build_args: &BuildArgs) -> Result<Build> {
-        (self.build_command)(platform, build_args)
+        Compiler::setup_build_script_env(platform)?;
+        (self.build_command)(platform, build_args).map_err(|e| {
+            if e.is::<DinghyError>() {
+                e
+            } else {
+                e.context(DinghyError::BuildFailed)
+            }
+        })
+    }
+
+    /// Sets the variables build scripts can rely on, a stable contract
+    /// documented in docs/vars.md.
+    pub fn setup_build_script_env(platform: &dyn Platform) -> Result<()> {
+        set_env("DINGHY_PLATFORM_ID", platform.id());
+        set_env("DINGHY_TARGET_TRIPLE", platform.rustc_triple());
+        match platform.sysroot()? {
+            Some(sysroot) => set_env("DINGHY_SYSROOT", sysroot),
+            None => env::remove_var("DINGHY_SYSROOT"),
+        }
+        Ok(())
     }
 
     pub fn clean(&self, platform: &dyn Platform) -> Result<()> {
@@ -98,41 +121,108 @@ impl ProjectMetadata {
     }
 }
 
-fn config(offline: bool, verbosity: u32) -> Result<Config> {
+fn config(
+    frozen: bool,
+    locked: bool,
+    offline: bool,
+    verbosity: u32,
+    cargo_config: &[String],
+) -> Result<Config> {
     let mut config = Config::default()?;
+    // The embedded cargo only takes --config overrides as an unstable option.
+    // Allowing it unlocks every nightly gate of the process, including the
+    // cargo-features of the manifests, not only this option.
+    let unstable_flags = if cargo_config.is_empty() {
+        vec![]
+    } else {
+        maybe_allow_nightly_features();
+        vec!["unstable-options".to_string()]
+    };
     config.configure(
         verbosity,
         false,
         None,
-        false,
-        false,
+        frozen,
+        locked,
         offline,
         &None,
-        &[],
-        &[],
+        &unstable_flags,
+        cargo_config,
     )?;
     Ok(config)
 }
 
-fn profile(release: bool, build_args: &BuildArgs) -> InternedString {
-    if release || build_args.compile_mode == cargo::util::command_prelude::CompileMode::Bench {
+fn profile(release: bool, profile: Option<&str>, build_args: &BuildArgs) -> InternedString {
+    if let Some(profile) = profile {
+        InternedString::new(profile)
+    } else if release || build_args.compile_mode == cargo::util::command_prelude::CompileMode::Bench
+    {
         InternedString::new("release")
     } else {
         InternedString::new("debug")
     }
 }
 
+/// Splits a `--features` value on whitespace and commas, like cargo does.
+fn parse_features(features: &str) -> Vec<String> {
+    features
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|feature| !feature.is_empty())
+        .map(|feature| feature.to_string())
+        .collect()
+}
+
+/// The default features of the platform followed by the requested ones.
+///
+/// Cargo only gives plain features to the package of the current directory,
+/// so the platform features it does not declare go to the workspace members it
+/// depends on, as `member/feature`. Those no such package declares are ignored.
+fn platform_features(
+    platform: &dyn Platform,
+    workspace: &Workspace,
+    requested: &[String],
+) -> Vec<String> {
+    let current = workspace.current_opt();
+    let mut features = vec![];
+    for feature in platform.configuration().default_features() {
+        let declares = |package: &Package| package.summary().features().contains_key(&*feature);
+        if feature.contains('/') || current.is_some_and(declares) {
+            features.push(feature);
+            continue;
+        }
+        let scoped = current
+            .into_iter()
+            .flat_map(|current| current.dependencies())
+            .filter(|dependency| {
+                workspace
+                    .members()
+                    .any(|member| member.name() == dependency.package_name() && declares(member))
+            })
+            .map(|dependency| format!("{}/{}", dependency.name_in_toml(), feature))
+            .unique()
+            .collect::<Vec<_>>();
+        if scoped.is_empty() {
+            warn!(
+                "No package of the build declares the default feature '{}' of platform {}, ignoring it",
+                feature,
+                platform.id()
+            );
+        }
+        features.extend(scoped);
+    }
+    features.extend(requested.iter().cloned());
+    features
+}
+
 fn create_build_command(
     matches: &ArgMatches,
-) -> Result<Box<dyn Fn(&dyn Platform, &BuildArgs) -> Result<Build>>> {
+) -> Result<Box<dyn Fn(&dyn Platform, &BuildArgs) -> Result<Build> + Send + Sync>> {
     let all = matches.is_present("ALL");
     let all_features = matches.is_present("ALL_FEATURES");
+    let auto_add_target = matches.is_present("AUTO_ADD_TARGET");
     let benches = arg_as_string_vec(matches, "BENCH");
     let bins = arg_as_string_vec(matches, "BIN");
-    let features: Vec<String> = matches
-        .value_of("FEATURES")
-        .map(|f| f.split(" ").map(|s| s.into()).collect())
-        .unwrap_or(vec![]);
+    let features = parse_features(matches.value_of("FEATURES").unwrap_or(""));
     let examples = arg_as_string_vec(matches, "EXAMPLE");
     let excludes = arg_as_string_vec(matches, "EXCLUDE");
     let jobs = matches.value_of("JOBS").map(|v| v.parse::<u32>().unwrap());
@@ -141,14 +231,18 @@ fn create_build_command(
     let packages = arg_as_string_vec(matches, "SPEC");
 
     let release = matches.is_present("RELEASE");
+    let profile_name = matches.value_of("PROFILE").map(|it| it.to_string());
     let tests = arg_as_string_vec(matches, "TEST");
     let bearded = matches.is_present("BEARDED");
+    let cargo_config = arg_as_string_vec(matches, "CARGO_CONFIG");
+    let frozen = matches.is_present("FROZEN");
+    let locked = matches.is_present("LOCKED");
     let offline = matches.is_present("OFFLINE");
     let verbosity = matches.occurrences_of("VERBOSE") as u32;
 
     let f = Box::new(move |platform: &dyn Platform, build_args: &BuildArgs| {
-        let config = config(offline, verbosity)?;
-        let requested_profile = profile(release, build_args);
+        let config = config(frozen, locked, offline, verbosity, &cargo_config)?;
+        let requested_profile = profile(release, profile_name.as_deref(), build_args);
         let root_manifest = find_root_manifest_for_wd(&current_dir()?)?;
         if current_dir()? == root_manifest.parent().unwrap() && features.len() > 0 {
             bail!("cargo does not support --features flag when building from root of workspace")
@@ -176,11 +270,10 @@ fn create_build_command(
                 .collect::<Vec<_>>();
 
             if filtered_packages.is_empty() {
-                bail!(
-                    "packages {:?} are filtered out on platform {:?}",
-                    packages,
-                    platform
-                )
+                bail!(DinghyError::PackagesFilteredOut {
+                    packages: packages.clone(),
+                    platform: platform.id(),
+                })
             } else {
                 (filtered_packages, vec![]) // Exclude not allowed with -p, hence empty vec.
             }
@@ -200,7 +293,7 @@ fn create_build_command(
 
         let compile_options = CompileOptions {
             build_config,
-            features: features.clone(),
+            features: platform_features(platform, &workspace, &features),
             all_features,
             no_default_features,
             spec: CompilePackages::from_flags(all, excludes, packages)?,
@@ -225,7 +318,23 @@ fn create_build_command(
         if bearded {
             setup_dinghy_wrapper(&workspace, platform)?;
         }
-        let compilation = ops::compile(&workspace, &compile_options)?;
+        if auto_add_target
+            && !platform.is_host()
+            && target_std_installed(platform.rustc_triple()) == Some(false)
+        {
+            add_target_with_rustup(platform.rustc_triple())?;
+        }
+        let compilation = ops::compile(&workspace, &compile_options).map_err(|e| {
+            let triple = platform.rustc_triple();
+            if !platform.is_host() && target_std_installed(triple) == Some(false) {
+                e.context(format!(
+                    "The standard library for {} is not installed, run `rustup target add {}`",
+                    triple, triple
+                ))
+            } else {
+                e
+            }
+        })?;
         let build = to_build(compilation, &config, build_args, platform)?;
         copy_dependencies_to_target(&build)?;
         Ok(build)
@@ -233,14 +342,73 @@ fn create_build_command(
     Ok(f)
 }
 
-fn create_clean_command(matches: &ArgMatches) -> Result<Box<dyn Fn(&dyn Platform) -> Result<()>>> {
+/// Whether the active rustc has the standard library of `rustc_triple`,
+/// `None` if rustc can not tell.
+fn target_std_installed(rustc_triple: &str) -> Option<bool> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .args(["--print", "target-libdir", "--target", rustc_triple])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let libdir = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+    Some(contains_file_with_prefix(&libdir, "libcore-"))
+}
+
+/// Installs the standard library of `rustc_triple`, skipped when rustup does
+/// not manage the toolchain.
+fn add_target_with_rustup(rustc_triple: &str) -> Result<()> {
+    let rustup_managed = env::var_os("RUSTC").is_none()
+        && Command::new("rustup")
+            .args(["which", "rustc"])
+            .output()
+            .is_ok_and(|it| it.status.success());
+    if !rustup_managed {
+        warn!(
+            "The toolchain is not managed by rustup, not adding target {}",
+            rustc_triple
+        );
+        return Ok(());
+    }
+    info!("Adding target {} with rustup", rustc_triple);
+    let mut command = Command::new("rustup");
+    command.args(["target", "add", rustc_triple]);
+    debug!("Running {:?}", command);
+    if !command
+        .status()
+        .with_context(|| format!("Couldn't run {:?}", command))?
+        .success()
+    {
+        bail!("Couldn't add target {} with rustup", rustc_triple)
+    }
+    Ok(())
+}
+
+fn contains_file_with_prefix(dir: &Path, prefix: &str) -> bool {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|it| it.ok())
+                .any(|it| it.file_name().to_string_lossy().starts_with(prefix))
+        })
+        .unwrap_or(false)
+}
+
+fn create_clean_command(
+    matches: &ArgMatches,
+) -> Result<Box<dyn Fn(&dyn Platform) -> Result<()> + Send + Sync>> {
     let packages = arg_as_string_vec(matches, "SPEC");
     let release = matches.is_present("RELEASE");
+    let cargo_config = arg_as_string_vec(matches, "CARGO_CONFIG");
+    let frozen = matches.is_present("FROZEN");
+    let locked = matches.is_present("LOCKED");
     let offline = matches.is_present("OFFLINE");
     let verbosity = matches.occurrences_of("VERBOSE") as u32;
-    let config = config(offline, verbosity)?;
 
     let f = Box::new(move |platform: &dyn Platform| {
+        let config = config(frozen, locked, offline, verbosity, &cargo_config)?;
         let workspace = Workspace::new(&find_root_manifest_for_wd(&current_dir()?)?, &config)?;
         let requested_profile = InternedString::new(if release { "release" } else { "debug" });
 
@@ -261,17 +429,12 @@ fn create_clean_command(matches: &ArgMatches) -> Result<Box<dyn Fn(&dyn Platform
 
 fn create_run_command(
     matches: &ArgMatches,
-) -> Result<Box<dyn Fn(&dyn Platform, &BuildArgs, &[&str]) -> Result<()>>> {
+) -> Result<Box<dyn Fn(&dyn Platform, &BuildArgs, &[&str]) -> Result<()> + Send + Sync>> {
     let all = matches.is_present("ALL");
     let all_features = matches.is_present("ALL_FEATURES");
     let benches = arg_as_string_vec(matches, "BENCH");
     let bins = arg_as_string_vec(matches, "BIN");
-    let features: Vec<String> = matches
-        .value_of("FEATURES")
-        .unwrap_or("")
-        .split(" ")
-        .map(|s| s.into())
-        .collect();
+    let features = parse_features(matches.value_of("FEATURES").unwrap_or(""));
     let examples = arg_as_string_vec(matches, "EXAMPLE");
     let excludes = arg_as_string_vec(matches, "EXCLUDE");
     let jobs = matches.value_of("JOBS").map(|v| v.parse::<u32>().unwrap());
@@ -280,14 +443,18 @@ fn create_run_command(
     let packages = arg_as_string_vec(matches, "SPEC");
 
     let release = matches.is_present("RELEASE");
+    let profile_name = matches.value_of("PROFILE").map(|it| it.to_string());
     let tests = arg_as_string_vec(matches, "TEST");
     let bearded = matches.is_present("BEARDED");
+    let cargo_config = arg_as_string_vec(matches, "CARGO_CONFIG");
+    let frozen = matches.is_present("FROZEN");
+    let locked = matches.is_present("LOCKED");
     let offline = matches.is_present("OFFLINE");
     let verbosity = matches.occurrences_of("VERBOSE") as u32;
 
     let f = Box::new(
         move |platform: &dyn Platform, build_args: &BuildArgs, args: &[&str]| {
-            let config = config(offline, verbosity)?;
+            let config = config(frozen, locked, offline, verbosity, &cargo_config)?;
             let workspace = Workspace::new(&find_root_manifest_for_wd(&current_dir()?)?, &config)?;
 
             let project_metadata_list = workskpace_metadata(&workspace)?;
@@ -300,7 +467,11 @@ fn create_run_command(
             } else {
                 excludes.clone()
             };
-            let requested_profile = InternedString::new(if release { "release" } else { "debug" });
+            let requested_profile = InternedString::new(match &profile_name {
+                Some(profile) => profile,
+                None if release => "release",
+                None => "debug",
+            });
 
             let build_config = CargoCoreCompiler::BuildConfig {
                 message_format: MessageFormat::Human,
@@ -316,7 +487,7 @@ fn create_run_command(
 
             let compile_options = CompileOptions {
                 build_config,
-                features: features.clone(),
+                features: platform_features(platform, &workspace, &features),
                 all_features,
                 no_default_features,
                 spec: CompilePackages::from_flags(all, excludes, packages.clone())?,
@@ -448,24 +619,26 @@ fn to_build(
             runnables: compilation
                 .binaries
                 .iter()
-                .map(|exe_path| {
+                .map(|(u, exe_path)| {
                     Ok(Runnable {
-                        exe: exe_path.1.clone(),
+                        exe: exe_path.clone(),
                         id: exe_path
-                            .1
                             .file_name()
                             .ok_or_else(|| {
-                                anyhow!("Invalid executable file '{}'", &exe_path.1.display())
+                                anyhow!("Invalid executable file '{}'", &exe_path.display())
                             })?
                             .to_str()
                             .ok_or_else(|| {
-                                anyhow!("Invalid executable file '{}'", &exe_path.1.display())
+                                anyhow!("Invalid executable file '{}'", &exe_path.display())
                             })?
                             .to_string(),
-                        source: PathBuf::from("."),
+                        source: u.pkg.package_id().source_id().url().to_file_path().unwrap(),
                     })
                 })
                 .collect::<Result<Vec<_>>>()?,
+            exec_prefix: None,
+            runner: None,
+            target: platform.as_cargo_kind(),
             target_path: compilation.root_output[&platform.as_cargo_kind()].clone(),
         }),
 
@@ -492,6 +665,9 @@ fn to_build(
                     })
                 })
                 .collect::<Result<Vec<_>>>()?,
+            exec_prefix: None,
+            runner: None,
+            target: platform.as_cargo_kind(),
             target_path: compilation.root_output[&platform.as_cargo_kind()].clone(),
         }),
     }
@@ -831,3 +1007,60 @@ fn workskpace_metadata(workspace: &Workspace) -> Result<Vec<ProjectMetadata>> {
         })
         .collect::<Result<_>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PlatformConfiguration;
+    use crate::host::HostPlatform;
+    use std::sync::Arc;
+
+    #[test]
+    fn cargo_config_overrides_apply() {
+        let config = config(false, false, false, 0, &["build.jobs=3".to_string()]).unwrap();
+        assert_eq!(config.build_config().unwrap().jobs, Some(3));
+    }
+
+    #[test]
+    fn features_split_like_cargo() {
+        assert_eq!(parse_features("a b  c"), vec!["a", "b", "c"]);
+        assert_eq!(parse_features("a,b,c"), vec!["a", "b", "c"]);
+        assert_eq!(
+            parse_features(" a, b,,c dep/d "),
+            vec!["a", "b", "c", "dep/d"]
+        );
+        assert!(parse_features("").is_empty());
+    }
+
+    #[test]
+    fn platform_default_features_apply_from_a_crate_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"mobile-app\"\nversion = \"0.1.0\"\n\n[features]\nmobile = []\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(
+            dir.path().join("src/lib.rs"),
+            "#[cfg(not(feature = \"mobile\"))]\ncompile_error!(\"mobile is off\");\n",
+        )
+        .unwrap();
+        let matches = clap::App::new("dinghy").get_matches_from(vec!["dinghy"]);
+        let compiler = Arc::new(Compiler::from_args(&matches).unwrap());
+        let mut configuration = PlatformConfiguration::empty();
+        configuration.default_features = Some(vec!["mobile".to_string()]);
+        let platform = HostPlatform::new(compiler.clone(), configuration).unwrap();
+        let build_args = BuildArgs {
+            compile_mode: CompileMode::Build,
+            ..crate::test_build(vec![]).build_args
+        };
+
+        crate::in_dir(dir.path(), || compiler.build(&platform, &build_args)).unwrap();
+    }
+
+    #[test]
+    fn host_std_is_installed() {
+        assert_eq!(target_std_installed(crate::HOST_TRIPLE), Some(true));
+    }
+}
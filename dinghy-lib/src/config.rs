@@ -82,40 +82,169 @@ impl<'de> de::Deserialize<'de> for TestDataConfiguration {
 pub struct Configuration {
     pub platforms: collections::BTreeMap<String, PlatformConfiguration>,
     pub ssh_devices: collections::BTreeMap<String, SshDeviceConfiguration>,
+    pub ssh_device_templates: collections::BTreeMap<String, toml::value::Table>,
+    /// ssh devices as written in the files, until their templates are resolved.
+    ssh_device_tables: collections::BTreeMap<String, toml::value::Table>,
     pub script_devices: collections::BTreeMap<String, ScriptDeviceConfiguration>,
+    pub android_devices: collections::BTreeMap<String, AndroidDeviceConfiguration>,
+    /// Variables set when building for any platform, before the platform `env`
+    pub env: collections::BTreeMap<String, String>,
     pub test_data: Vec<TestData>,
+    pub adb_path: Option<String>,
+    pub android_ndk_version: Option<String>,
+    pub app_process_class_path: Option<String>,
+    pub app_process_main_class: Option<String>,
+    pub bundle_namespace: Option<String>,
+    pub default_device: Option<String>,
+    pub device_provided_libs: Option<Vec<String>>,
+    pub device_timeout: Option<u64>,
+    pub ios_app_id: Option<String>,
+    pub ios_device_family: Option<Vec<IosDeviceFamily>>,
+    pub ios_hardened_runtime: Option<bool>,
+    pub ios_keychain: Option<String>,
+    pub ios_min_os_version: Option<String>,
+    pub ios_provisioning_profile: Option<String>,
+    pub ios_timestamp: Option<bool>,
+    pub launch: Option<Launch>,
+    pub run_as_package: Option<String>,
+    pub run_cwd: Option<String>,
+    pub set_dinghy_env: Option<bool>,
+    pub set_rpath: Option<bool>,
+    pub shared_test_data: Option<bool>,
+    pub transfer_compression: Option<Compression>,
+    pub verified_test_data: Option<Vec<String>>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 struct ConfigurationFileContent {
     pub platforms: Option<collections::BTreeMap<String, PlatformConfiguration>>,
-    pub ssh_devices: Option<collections::BTreeMap<String, SshDeviceConfiguration>>,
+    pub ssh_devices: Option<collections::BTreeMap<String, toml::value::Table>>,
+    pub ssh_device_templates: Option<collections::BTreeMap<String, toml::value::Table>>,
     pub script_devices: Option<collections::BTreeMap<String, ScriptDeviceConfiguration>>,
+    pub android_devices: Option<collections::BTreeMap<String, AndroidDeviceConfiguration>>,
+    pub env: Option<collections::BTreeMap<String, String>>,
     pub test_data: Option<collections::BTreeMap<String, TestDataConfiguration>>,
+    pub adb_path: Option<String>,
+    pub android_ndk_version: Option<String>,
+    pub app_process_class_path: Option<String>,
+    pub app_process_main_class: Option<String>,
+    pub bundle_namespace: Option<String>,
+    pub default_device: Option<String>,
+    pub device_provided_libs: Option<Vec<String>>,
+    pub device_timeout: Option<u64>,
+    pub ios_app_id: Option<String>,
+    pub ios_device_family: Option<Vec<IosDeviceFamily>>,
+    pub ios_hardened_runtime: Option<bool>,
+    pub ios_keychain: Option<String>,
+    pub ios_min_os_version: Option<String>,
+    pub ios_provisioning_profile: Option<String>,
+    pub ios_timestamp: Option<bool>,
+    pub launch: Option<Launch>,
+    pub run_as_package: Option<String>,
+    pub run_cwd: Option<String>,
+    pub set_dinghy_env: Option<bool>,
+    pub set_rpath: Option<bool>,
+    pub shared_test_data: Option<bool>,
+    pub transfer_compression: Option<Compression>,
+    pub verified_test_data: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn program(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "tar.gz",
+            Compression::Zstd => "tar.zst",
+        }
+    }
+}
+
+/// How runnables are started on Android devices.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Launch {
+    /// The executable is run directly
+    Exec,
+    /// The executable is started by a java class run with `app_process`, see
+    /// `app_process_class_path` and `app_process_main_class`
+    AppProcess,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IosDeviceFamily {
+    Iphone,
+    Ipad,
+}
+
+impl IosDeviceFamily {
+    /// Value of the family in the `UIDeviceFamily` array of an Info.plist.
+    pub fn plist_value(&self) -> u8 {
+        match self {
+            IosDeviceFamily::Iphone => 1,
+            IosDeviceFamily::Ipad => 2,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct PlatformConfiguration {
+    pub cxx_stdlib: Option<String>,
+    pub cxx_stdlib_path: Option<String>,
     pub deb_multiarch: Option<String>,
+    pub default_features: Option<Vec<String>>,
     pub env: Option<collections::HashMap<String, String>>,
+    pub exec_prefix: Option<String>,
+    pub linker_args: Option<Vec<String>>,
+    pub overlay_depth: Option<usize>,
     pub overlays: Option<collections::HashMap<String, OverlayConfiguration>>,
+    pub runner: Option<String>,
+    pub runtime_env: Option<collections::HashMap<String, String>>,
     pub rustc_triple: Option<String>,
+    pub strict_overlays: Option<bool>,
     pub sysroot: Option<String>,
     pub toolchain: Option<String>,
 }
 
 impl PlatformConfiguration {
-    pub fn empty() -> Self {
+    pub const fn empty() -> Self {
         PlatformConfiguration {
+            cxx_stdlib: None,
+            cxx_stdlib_path: None,
             deb_multiarch: None,
+            default_features: None,
             env: None,
+            exec_prefix: None,
+            linker_args: None,
+            overlay_depth: None,
             overlays: None,
+            runner: None,
+            runtime_env: None,
             rustc_triple: None,
+            strict_overlays: None,
             sysroot: None,
             toolchain: None,
         }
     }
 
+    /// Features always enabled when building for the platform.
+    pub fn default_features(&self) -> Vec<String> {
+        self.default_features.clone().unwrap_or(vec![])
+    }
+
     pub fn env(&self) -> Vec<(String, String)> {
         self.env
             .as_ref()
@@ -126,12 +255,24 @@ impl PlatformConfiguration {
             })
             .unwrap_or(vec![])
     }
+
+    /// Variables set when running, sorted by name.
+    pub fn runtime_env(&self) -> Vec<(String, String)> {
+        self.runtime_env
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .sorted()
+            .collect_vec()
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct OverlayConfiguration {
     pub path: String,
     pub scope: Option<String>,
+    #[serde(rename = "static")]
+    pub static_libs: Option<bool>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -146,6 +287,39 @@ pub struct SshDeviceConfiguration {
     #[serde(default)]
     pub remote_shell_vars: collections::HashMap<String, String>,
     pub install_adhoc_rsync_local_path: Option<String>,
+    #[serde(default)]
+    pub extra_library_paths: Vec<String>,
+    pub transfer: Option<Transfer>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+    /// Prepended to the executable on the device, e.g. a dynamic loader
+    pub exec_prefix: Option<String>,
+    /// Wait for the device to come back and run again when it closes the
+    /// connection, e.g. tests rebooting it
+    pub reconnect_after_reboot: Option<bool>,
+    /// Bandwidth limit of the transfers to the device, in KiB/s
+    pub rsync_bwlimit: Option<u32>,
+    /// Copy the bundle and its libraries at the same time
+    pub parallel_sync: Option<bool>,
+}
+
+/// Tool copying bundles to ssh devices.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    Rsync,
+    Scp,
+}
+
+/// Settings of an Android device, keyed by its adb serial.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct AndroidDeviceConfiguration {
+    /// Prepended to the executable on the device, e.g. a dynamic loader
+    pub exec_prefix: Option<String>,
+    /// Rustc triple to build for when only the device is given
+    pub preferred_target: Option<String>,
+    /// SELinux context the installed executable is relabeled with
+    pub selinux_context: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -160,10 +334,23 @@ impl Configuration {
         if let Some(pfs) = other.platforms {
             self.platforms.extend(pfs)
         }
-        self.ssh_devices
+        self.ssh_device_tables
             .extend(other.ssh_devices.unwrap_or(collections::BTreeMap::new()));
+        self.ssh_device_templates.extend(
+            other
+                .ssh_device_templates
+                .unwrap_or(collections::BTreeMap::new()),
+        );
         self.script_devices
             .extend(other.script_devices.unwrap_or(collections::BTreeMap::new()));
+        self.android_devices.extend(
+            other
+                .android_devices
+                .unwrap_or(collections::BTreeMap::new()),
+        );
+        for (key, value) in other.env.unwrap_or(collections::BTreeMap::new()) {
+            self.env.entry(key).or_insert(value);
+        }
         for (id, source) in other.test_data.unwrap_or(collections::BTreeMap::new()) {
             // TODO Remove key
             self.test_data.push(TestData {
@@ -174,6 +361,108 @@ impl Configuration {
                 copy_git_ignored: source.copy_git_ignored,
             })
         }
+        if self.adb_path.is_none() {
+            self.adb_path = other.adb_path;
+        }
+        if self.android_ndk_version.is_none() {
+            self.android_ndk_version = other.android_ndk_version;
+        }
+        if self.app_process_class_path.is_none() {
+            self.app_process_class_path = other.app_process_class_path;
+        }
+        if self.app_process_main_class.is_none() {
+            self.app_process_main_class = other.app_process_main_class;
+        }
+        if self.bundle_namespace.is_none() {
+            self.bundle_namespace = other.bundle_namespace;
+        }
+        if self.default_device.is_none() {
+            self.default_device = other.default_device;
+        }
+        if self.device_provided_libs.is_none() {
+            self.device_provided_libs = other.device_provided_libs;
+        }
+        if self.device_timeout.is_none() {
+            self.device_timeout = other.device_timeout;
+        }
+        if self.ios_app_id.is_none() {
+            self.ios_app_id = other.ios_app_id;
+        }
+        if self.ios_device_family.is_none() {
+            self.ios_device_family = other.ios_device_family;
+        }
+        if self.ios_hardened_runtime.is_none() {
+            self.ios_hardened_runtime = other.ios_hardened_runtime;
+        }
+        if self.ios_keychain.is_none() {
+            self.ios_keychain = other.ios_keychain;
+        }
+        if self.ios_min_os_version.is_none() {
+            self.ios_min_os_version = other.ios_min_os_version;
+        }
+        if self.ios_provisioning_profile.is_none() {
+            self.ios_provisioning_profile = other.ios_provisioning_profile;
+        }
+        if self.ios_timestamp.is_none() {
+            self.ios_timestamp = other.ios_timestamp;
+        }
+        if self.launch.is_none() {
+            self.launch = other.launch;
+        }
+        if self.run_as_package.is_none() {
+            self.run_as_package = other.run_as_package;
+        }
+        if self.run_cwd.is_none() {
+            self.run_cwd = other.run_cwd;
+        }
+        if self.set_dinghy_env.is_none() {
+            self.set_dinghy_env = other.set_dinghy_env;
+        }
+        if self.set_rpath.is_none() {
+            self.set_rpath = other.set_rpath;
+        }
+        if self.shared_test_data.is_none() {
+            self.shared_test_data = other.shared_test_data;
+        }
+        if self.transfer_compression.is_none() {
+            self.transfer_compression = other.transfer_compression;
+        }
+        if self.verified_test_data.is_none() {
+            self.verified_test_data = other.verified_test_data;
+        }
+        Ok(())
+    }
+
+    /// Builds the ssh devices from the merged files: a device with
+    /// `extends = "template"` takes the fields it does not set from the
+    /// `ssh_device_templates` entry of that name.
+    fn resolve_ssh_devices(&mut self) -> Result<()> {
+        for (id, table) in &self.ssh_device_tables {
+            let mut resolved = match table.get("extends") {
+                Some(template) => {
+                    let template = template
+                        .as_str()
+                        .ok_or_else(|| anyhow!("extends of ssh device {} must be a string", id))?;
+                    self.ssh_device_templates
+                        .get(template)
+                        .cloned()
+                        .ok_or_else(|| {
+                            anyhow!("ssh device {} extends unknown template {}", id, template)
+                        })?
+                }
+                None => toml::value::Table::new(),
+            };
+            resolved.extend(
+                table
+                    .iter()
+                    .filter(|(key, _)| *key != "extends")
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+            let device = toml::Value::Table(resolved)
+                .try_into()
+                .with_context(|| format!("Invalid configuration for ssh device {}", id))?;
+            self.ssh_devices.insert(id.clone(), device);
+        }
         Ok(())
     }
 }
@@ -215,6 +504,7 @@ pub fn dinghy_config<P: AsRef<path::Path>>(dir: P) -> Result<Configuration> {
             trace!("No configuration found at {:?}", file);
         }
     }
+    conf.resolve_ssh_devices()?;
     Ok(conf)
 }
 
@@ -229,4 +519,47 @@ mod tests {
             .join("../../../test-ws/test-app/.dinghy.toml");
         super::read_config_file(config_file).unwrap();
     }
+
+    #[test]
+    fn ssh_devices_extend_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(".dinghy.toml");
+        ::std::fs::write(
+            &file,
+            r#"
+            [ssh_device_templates.board]
+            username = "root"
+            port = 2222
+            platform = "board-platform"
+
+            [ssh_devices]
+            board-1 = { hostname = "board-1.local", extends = "board" }
+            board-2 = { hostname = "board-2.local", extends = "board", port = 22 }
+            "#,
+        )
+        .unwrap();
+        let mut conf = super::Configuration::default();
+        conf.merge(&file).unwrap();
+        conf.resolve_ssh_devices().unwrap();
+        let board_1 = &conf.ssh_devices["board-1"];
+        assert_eq!(board_1.hostname, "board-1.local");
+        assert_eq!(board_1.username, "root");
+        assert_eq!(board_1.port, Some(2222));
+        assert_eq!(board_1.platform.as_deref(), Some("board-platform"));
+        assert_eq!(conf.ssh_devices["board-2"].port, Some(22));
+    }
+
+    #[test]
+    fn nearest_global_env_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let near = dir.path().join("near.toml");
+        let far = dir.path().join("far.toml");
+        ::std::fs::write(&near, "[env]\nOPENSSL_DIR = \"/near\"\n").unwrap();
+        ::std::fs::write(&far, "[env]\nOPENSSL_DIR = \"/far\"\nCC_FLAGS = \"-O2\"\n").unwrap();
+        let mut conf = super::Configuration::default();
+        conf.merge(&near).unwrap();
+        conf.merge(&far).unwrap();
+        assert_eq!(conf.env["OPENSSL_DIR"], "/near");
+        assert_eq!(conf.env["CC_FLAGS"], "-O2");
+    }
 }
@@ -1,12 +1,36 @@
+use crate::config::Compression;
 use crate::errors::*;
 use crate::project;
 use crate::project::Project;
 use crate::utils::copy_and_sync_file;
+use crate::utils::path_to_str;
+use crate::utils::target_dir;
 use crate::Build;
 use crate::BuildBundle;
+use crate::Device;
 use crate::Runnable;
+use cargo::core::compiler::CompileMode;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+/// Name of the manifest written in the bundle with `--bundle-manifest`.
+pub const BUNDLE_MANIFEST: &str = "dinghy-manifest.txt";
+
+/// Directory of the bundle root holding the `test_data` of each crate with
+/// `shared_test_data`.
+pub const SHARED_TEST_DATA_DIR: &str = "shared_test_data";
 
 pub fn make_remote_app(
     project: &Project,
@@ -16,6 +40,28 @@ pub fn make_remote_app(
     make_remote_app_with_name(project, build, runnable, None)
 }
 
+/// Copies the shared `test_data` of the bundle to the device with `copy`,
+/// given the host and device directories, once per crate and run.
+/// `installed` holds the device directories already copied.
+pub fn install_shared_test_data<F: FnOnce(&Path, &Path) -> Result<()>>(
+    build_bundle: &BuildBundle,
+    remote_bundle: &BuildBundle,
+    installed: &Mutex<HashSet<PathBuf>>,
+    copy: F,
+) -> Result<()> {
+    let (local, remote) = match (&build_bundle.test_data_dir, &remote_bundle.test_data_dir) {
+        (Some(local), Some(remote)) => (local, remote),
+        _ => return Ok(()),
+    };
+    let mut installed = installed.lock().unwrap();
+    if installed.contains(remote) {
+        return Ok(());
+    }
+    copy(local, remote)?;
+    installed.insert(remote.clone());
+    Ok(())
+}
+
 pub fn make_remote_app_with_name(
     project: &Project,
     build: &Build,
@@ -35,8 +81,9 @@ pub fn make_remote_app_with_name(
                 && !path.to_str().unwrap().contains("android"))
     }
 
+    // The namespace may come from the command line, not only the crate config
+    let root_dir = build.target_path.join(project.bundle_namespace()?);
     let project = project.for_runnable(runnable)?;
-    let root_dir = build.target_path.join("dinghy");
     let bundle_path = match bundle_name {
         Some(name) => root_dir.join(&runnable.id).join(name),
         None => root_dir.join(&runnable.id),
@@ -69,6 +116,10 @@ pub fn make_remote_app_with_name(
             &bundle_exe_path.display()
         )
     })?;
+    // Named bundles are iOS apps, patchelf only handles ELF executables
+    if project.sets_rpath() && bundle_name.is_none() {
+        set_bundle_rpath(&bundle_exe_path, &root_dir, &bundle_libs_path)?;
+    }
 
     debug!("Copying dynamic libs to bundle");
     for src_lib_path in &build.dynamic_libraries {
@@ -77,7 +128,12 @@ pub fn make_remote_app_with_name(
                 .file_name()
                 .ok_or_else(|| anyhow!("Invalid file name {:?}", src_lib_path.file_name()))?,
         );
-        if !is_sysroot_library(&src_lib_path) {
+        if project.is_device_provided_lib(src_lib_path) {
+            debug!(
+                "Dynamic lib {} will not be copied as the device provides it",
+                src_lib_path.display()
+            );
+        } else if !is_sysroot_library(&src_lib_path) {
             debug!(
                 "Copying dynamic lib {} to {}",
                 src_lib_path.display(),
@@ -109,14 +165,889 @@ pub fn make_remote_app_with_name(
         false,
         &[runnable.source.join("target")],
     )?;
-    debug!("Copying test_data to bundle {}", bundle_path.display());
-    project.copy_test_data(&bundle_path)?;
+    // Named bundles are iOS apps, they must be self-contained
+    let test_data_dir = if project.shares_test_data() && bundle_name.is_none() {
+        link_shared_test_data(&project, runnable, &root_dir, &bundle_path)?
+    } else {
+        debug!("Copying test_data to bundle {}", bundle_path.display());
+        project.copy_test_data(&bundle_path)?;
+        None
+    };
 
-    Ok(BuildBundle {
+    let build_bundle = BuildBundle {
         id: runnable.id.clone(),
         bundle_dir: bundle_path.to_path_buf(),
         bundle_exe: bundle_exe_path.to_path_buf(),
         lib_dir: bundle_libs_path.to_path_buf(),
         root_dir,
+        test_data_dir,
+    };
+    if build.build_args.bundle_manifest {
+        write_bundle_manifest(&build_bundle)?;
+    }
+    Ok(build_bundle)
+}
+
+/// Points the rpath of the bundled executable to the bundle libraries, as a
+/// path relative to the executable. Skipped with a warning without patchelf.
+fn set_bundle_rpath(bundle_exe: &Path, root_dir: &Path, lib_dir: &Path) -> Result<()> {
+    if which::which("patchelf").is_err() {
+        warn!(
+            "patchelf not found on the host, not setting the rpath of {}",
+            bundle_exe.display()
+        );
+        return Ok(());
+    }
+    // bundle_dir is right below root_dir
+    let rpath = format!(
+        "$ORIGIN/../{}",
+        path_to_str(lib_dir.strip_prefix(root_dir)?)?
+    );
+    let mut command = Command::new("patchelf");
+    command.arg("--set-rpath").arg(&rpath).arg(bundle_exe);
+    debug!("Running {:?}", command);
+    if !command.status()?.success() {
+        bail!(
+            "Error setting the rpath of {} ({:?})",
+            bundle_exe.display(),
+            command
+        )
+    }
+    Ok(())
+}
+
+/// Copies the `test_data` of the crate of `runnable` in the shared directory
+/// of the bundle root, and links the bundle `test_data` to it with a relative
+/// link, valid once both are installed on the device.
+#[cfg(unix)]
+fn link_shared_test_data(
+    project: &Project,
+    runnable: &Runnable,
+    root_dir: &Path,
+    bundle_path: &Path,
+) -> Result<Option<PathBuf>> {
+    let crate_dir_name = runnable
+        .source
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid crate path {}", runnable.source.display()))?;
+    let shared_path = root_dir.join(SHARED_TEST_DATA_DIR).join(crate_dir_name);
+    debug!("Copying test_data to {}", shared_path.display());
+    let _ = fs::remove_dir_all(&shared_path);
+    project.copy_test_data(&shared_path)?;
+    let link_path = bundle_path.join("test_data");
+    // the crate sources may come with their own test_data
+    let _ = fs::remove_dir_all(&link_path);
+    std::os::unix::fs::symlink(
+        Path::new("..")
+            .join(SHARED_TEST_DATA_DIR)
+            .join(crate_dir_name)
+            .join("test_data"),
+        &link_path,
+    )
+    .with_context(|| format!("Couldn't link {}", link_path.display()))?;
+    Ok(Some(shared_path.join("test_data")))
+}
+
+#[cfg(not(unix))]
+fn link_shared_test_data(
+    project: &Project,
+    _runnable: &Runnable,
+    _root_dir: &Path,
+    bundle_path: &Path,
+) -> Result<Option<PathBuf>> {
+    warn!("shared_test_data needs a unix host, copying test_data to the bundle");
+    project.copy_test_data(bundle_path)?;
+    Ok(None)
+}
+
+/// Writes the sorted paths, relative to the bundle root, and sizes of every
+/// file in the bundle and its libraries, and echoes them on stdout.
+fn write_bundle_manifest(build_bundle: &BuildBundle) -> Result<()> {
+    let manifest_path = build_bundle.bundle_dir.join(BUNDLE_MANIFEST);
+    let mut entries = vec![];
+    for dir in &[&build_bundle.bundle_dir, &build_bundle.lib_dir] {
+        for entry in WalkDir::new(dir) {
+            let entry = entry?;
+            if entry.file_type().is_dir() || entry.path() == manifest_path {
+                continue;
+            }
+            entries.push(format!(
+                "{}\t{}",
+                path_to_str(entry.path().strip_prefix(&build_bundle.root_dir)?)?,
+                entry.metadata()?.len()
+            ));
+        }
+    }
+    entries.sort();
+    let manifest: String = entries.iter().map(|it| format!("{}\n", it)).collect();
+    fs::write(&manifest_path, &manifest)
+        .with_context(|| format!("Couldn't write {}", manifest_path.display()))?;
+    print!("{}", manifest);
+    Ok(())
+}
+
+/// Adds to `envs` the `RUST_BACKTRACE` the runnables get on every device:
+/// on, unless `envs` already sets it or, for runnables started on the host,
+/// it is `inherited` from the environment of dinghy. `no_backtrace` turns it
+/// off in all cases.
+pub fn add_backtrace_env(envs: &mut Vec<String>, no_backtrace: bool, inherited: bool) {
+    if no_backtrace {
+        envs.push("RUST_BACKTRACE=0".to_string());
+    } else if !inherited && !envs.iter().any(|env| env.starts_with("RUST_BACKTRACE=")) {
+        envs.push("RUST_BACKTRACE=1".to_string());
+    }
+}
+
+/// Shell command running a bundle installed on a unix-like remote device.
+///
+/// Shared by ssh and android devices so that both start the executable from
+/// the same place in the same bundle layout: the executable, the project
+/// sources and `test_data` all sit in `bundle_dir`. `library_paths` are
+/// searched by the dynamic loader after the bundle libraries, and
+/// `exec_prefix` is put right before the executable, after the runner wrapper.
+pub fn remote_run_command(
+    project: &Project,
+    build: &Build,
+    remote_bundle: &BuildBundle,
+    library_paths: &[String],
+    exec_prefix: Option<&str>,
+    envs: &[&str],
+    args: &[String],
+) -> Result<String> {
+    let mut ld_library_path = path_to_str(&remote_bundle.lib_dir)?.to_string();
+    for path in library_paths {
+        ld_library_path.push(':');
+        ld_library_path.push_str(path);
+    }
+    let trace = match &build.build_args.trace {
+        Some(tool) => format!(
+            "{} -f -o '{}'",
+            tool,
+            path_to_str(&remote_trace_file(remote_bundle))?
+        ),
+        None => String::new(),
+    };
+    Ok(format!(
+        "cd '{}' ; {} {} LD_LIBRARY_PATH=\"{}:$LD_LIBRARY_PATH\" {} {} {} {} {} {}",
+        path_to_str(&project.run_dir(&remote_bundle.bundle_dir))?,
+        envs.join(" "),
+        if project.sets_dinghy_env() {
+            "DINGHY=1"
+        } else {
+            ""
+        },
+        ld_library_path,
+        trace,
+        build.exec_prefix.as_deref().unwrap_or(""),
+        exec_prefix.unwrap_or(""),
+        path_to_str(&remote_bundle.bundle_exe)?,
+        if build.build_args.compile_mode == CompileMode::Bench {
+            "--bench"
+        } else {
+            ""
+        },
+        args.join(" ")
+    ))
+}
+
+/// Where `--trace` makes the tool write the trace on the device.
+fn remote_trace_file(remote_bundle: &BuildBundle) -> PathBuf {
+    remote_bundle.bundle_dir.join("dinghy-trace.txt")
+}
+
+/// Copies the trace written by `--trace` back to the host, under
+/// `dinghy-traces` in the target directory. A missing trace is only warned
+/// about, the status of the runnable matters more.
+pub fn fetch_trace(
+    device: &dyn Device,
+    tool: &str,
+    runnable: &Runnable,
+    remote_bundle: &BuildBundle,
+) {
+    let fetch = || -> Result<PathBuf> {
+        let local = target_dir()?.join("dinghy-traces").join(format!(
+            "{}-{}.{}",
+            device.id(),
+            runnable.id,
+            tool
+        ));
+        device.pull(path_to_str(&remote_trace_file(remote_bundle))?, &local)?;
+        Ok(local)
+    };
+    match fetch() {
+        Ok(local) => info!("{} of {} written to {}", tool, runnable.id, local.display()),
+        Err(e) => warn!("Couldn't fetch the {} of {}: {:?}", tool, runnable.id, e),
+    }
+}
+
+/// Where criterion writes its results when a bench runs on the device, and
+/// where they are copied back on the host.
+pub fn criterion_dirs(
+    project: &Project,
+    remote_bundle: &BuildBundle,
+) -> Result<(PathBuf, PathBuf)> {
+    Ok((
+        project
+            .run_dir(&remote_bundle.bundle_dir)
+            .join("target")
+            .join("criterion"),
+        target_dir()?.join("criterion"),
+    ))
+}
+
+/// Processes a remote lldb platform can debug at the same time, each one
+/// through a gdbserver port of its own.
+const LLDB_GDBSERVER_PORTS: u16 = 4;
+
+/// Ports of the gdbservers the lldb platform forwarded to `local_port` spawns
+/// for the debugged processes. lldb connects to them with the number the
+/// device gave, so they are forwarded to the same port on the host, right
+/// above `local_port`.
+pub fn lldb_gdbserver_ports(local_port: u16) -> Result<Range<u16>> {
+    match local_port
+        .checked_add(1)
+        .and_then(|min| Some(min..min.checked_add(LLDB_GDBSERVER_PORTS)?))
+    {
+        Some(ports) => Ok(ports),
+        None => bail!("No room for the gdbserver ports above port {}", local_port),
+    }
+}
+
+/// Command starting `lldb_server` in platform mode. It listens on a port of
+/// its choice, written to `port_file`, and spawns its gdbservers on
+/// `gdbserver_ports`.
+pub fn lldb_platform_command(
+    lldb_server: &str,
+    port_file: &str,
+    gdbserver_ports: &Range<u16>,
+) -> String {
+    format!(
+        "{} platform --server --listen 127.0.0.1:0 --socket-file {} --min-gdbserver-port {} --max-gdbserver-port {}",
+        lldb_server, port_file, gdbserver_ports.start, gdbserver_ports.end
+    )
+}
+
+/// Waits for the lldb platform to write the port it listens on, `read_port_file`
+/// reading its port file on the device.
+pub fn wait_for_lldb_platform_port<F: FnMut() -> Result<String>>(
+    mut read_port_file: F,
+) -> Result<u16> {
+    for _ in 0..20 {
+        if let Some(port) = read_port_file()
+            .ok()
+            .and_then(|it| it.trim().parse::<u16>().ok())
+        {
+            return Ok(port);
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    bail!("lldb-server did not report the port it listens on")
+}
+
+/// Packs the bundle and its libraries in a single compressed archive, next to
+/// them in the bundle root directory.
+pub fn compress_bundle(build_bundle: &BuildBundle, compression: Compression) -> Result<PathBuf> {
+    let archive =
+        build_bundle
+            .root_dir
+            .join(format!("{}.{}", build_bundle.id, compression.extension()));
+    let mut command = Command::new("tar");
+    command
+        .arg(format!("--use-compress-program={}", compression.program()))
+        .arg("-cf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&build_bundle.root_dir)
+        .arg(
+            build_bundle
+                .bundle_dir
+                .strip_prefix(&build_bundle.root_dir)?,
+        )
+        .arg(build_bundle.lib_dir.strip_prefix(&build_bundle.root_dir)?);
+    debug!("Running {:?}", command);
+    if !command.status()?.success() {
+        bail!("Error compressing bundle ({:?})", command)
+    }
+    Ok(archive)
+}
+
+/// Shell command checking the remote device can extract an archive made by
+/// `compress_bundle`.
+pub fn remote_uncompress_check_command(compression: Compression) -> String {
+    format!(
+        "command -v tar >/dev/null && command -v {} >/dev/null",
+        compression.program()
+    )
+}
+
+/// Shell command extracting, on the remote device, an archive made by
+/// `compress_bundle` and pushed to `remote_archive`.
+pub fn remote_uncompress_command(
+    remote_bundle: &BuildBundle,
+    remote_archive: &Path,
+    compression: Compression,
+) -> Result<String> {
+    Ok(format!(
+        "cd '{}' && {} -dc '{}' | tar -xf - && rm '{}'",
+        path_to_str(&remote_bundle.root_dir)?,
+        compression.program(),
+        path_to_str(remote_archive)?,
+        path_to_str(remote_archive)?,
+    ))
+}
+
+/// With `--verify-test-data`, checks the `verified_test_data` files of the
+/// crate of `runnable` have the same sha256 in the `test_data` of
+/// `remote_bundle` as in the one of `build_bundle`. `remote_output` runs a shell
+/// command on the device and returns its output.
+pub fn verify_test_data<F: FnOnce(&str) -> Result<String>>(
+    project: &Project,
+    build: &Build,
+    runnable: &Runnable,
+    build_bundle: &BuildBundle,
+    remote_bundle: &BuildBundle,
+    device_id: &str,
+    remote_output: F,
+) -> Result<()> {
+    if !build.build_args.verify_test_data {
+        return Ok(());
+    }
+    let files = project
+        .for_runnable(runnable)?
+        .conf
+        .verified_test_data
+        .clone()
+        .unwrap_or_default();
+    if files.is_empty() {
+        warn!(
+            "No verified_test_data configured for {}, nothing to verify",
+            runnable.id
+        );
+        return Ok(());
+    }
+    debug!("Verify test_data {:?} on {}", files, device_id);
+    let output = remote_output(&format!(
+        "cd '{}' && sha256sum {}",
+        path_to_str(&remote_bundle.bundle_dir.join("test_data"))?,
+        files
+            .iter()
+            .map(|file| ::shell_escape::escape(file.into()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    ))?;
+    let remote_sums = output
+        .lines()
+        .filter_map(|line| {
+            let (sum, file) = line.trim_end().split_once(' ')?;
+            // sha256sum marks the files read in binary mode with a '*'
+            Some((file.trim_start().trim_start_matches('*'), sum))
+        })
+        .collect::<HashMap<_, _>>();
+    if remote_sums.is_empty() {
+        bail!(
+            "Couldn't compute the sha256 of the test_data on {}, is sha256sum available there?",
+            device_id
+        )
+    }
+    let mut failures = vec![];
+    for file in &files {
+        let local_sum = sha256(&build_bundle.bundle_dir.join("test_data").join(file))?;
+        match remote_sums.get(file.as_str()) {
+            Some(remote_sum) if *remote_sum == local_sum => {}
+            Some(remote_sum) => failures.push(format!(
+                "{} differs (sha256 {} on the host, {} on the device)",
+                file, local_sum, remote_sum
+            )),
+            None => failures.push(format!("{} is missing", file)),
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "test_data of {} is corrupted on {}: {}",
+            runnable.id,
+            device_id,
+            failures.join(", ")
+        )
+    }
+    Ok(())
+}
+
+fn sha256(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("Couldn't open test_data file {}", path.display()))?;
+    let mut hasher = crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA256);
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Log files already opened by this dinghy invocation, so that runnables
+/// sharing a log file append to it instead of overwriting each other.
+static OPENED_LOG_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Path of the `--log-file` of `runnable` on `device_id`, with the `{runnable}`
+/// and `{device}` placeholders replaced.
+pub fn log_file_path(build: &Build, device_id: &str, runnable: &Runnable) -> Option<PathBuf> {
+    build.build_args.log_file.as_ref().map(|log_file| {
+        PathBuf::from(
+            log_file
+                .to_string_lossy()
+                .replace("{runnable}", &runnable.id)
+                .replace("{device}", device_id),
+        )
     })
 }
+
+/// Opens the `--log-file` of `runnable` on `device_id`, if any. It is
+/// truncated the first time it is opened and appended to afterwards.
+pub fn open_log_file(
+    build: &Build,
+    device_id: &str,
+    runnable: &Runnable,
+) -> Result<Option<fs::File>> {
+    let path = match log_file_path(build, device_id, runnable) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mut opened = OPENED_LOG_FILES.lock().unwrap();
+    let append = opened.contains(&path);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(&path)
+        .with_context(|| format!("Couldn't open log file {}", path.display()))?;
+    if !append {
+        opened.push(path);
+    }
+    Ok(Some(file))
+}
+
+thread_local! {
+    static OUTPUT_PREFIX: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Prefixes each line the runnables started from the current thread print
+/// with `prefix`, so the output of devices running at the same time can be
+/// told apart.
+pub fn prefix_output(prefix: Option<String>) {
+    OUTPUT_PREFIX.with(|it| *it.borrow_mut() = prefix);
+}
+
+/// Whether the output of the runnables is prefixed on the current thread. It
+/// then has to go through `run_and_tee`.
+pub fn output_prefixed() -> bool {
+    OUTPUT_PREFIX.with(|it| it.borrow().is_some())
+}
+
+/// Runs `command` with its stdout and stderr piped and copies them, as they
+/// come, to our own stdout and stderr and to `log`. Returns the exit status
+/// and the captured stdout.
+pub fn run_and_tee(command: &mut Command, log: Option<fs::File>) -> Result<(ExitStatus, Vec<u8>)> {
+    debug!("Running {:?}", command);
+    let prefix = OUTPUT_PREFIX.with(|it| it.borrow().clone());
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Couldn't run {:?}", command))?;
+    let log = Arc::new(Mutex::new(log));
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdout_log = log.clone();
+    let stdout_prefix = prefix.clone();
+    let stdout_thread = thread::spawn(move || {
+        let mut captured = vec![];
+        tee(
+            stdout,
+            io::stdout(),
+            &stdout_log,
+            Some(&mut captured),
+            stdout_prefix.as_deref(),
+        )
+        .map(|_| captured)
+    });
+    tee(stderr, io::stderr(), &log, None, prefix.as_deref())?;
+    let captured = stdout_thread
+        .join()
+        .map_err(|_| anyhow!("Couldn't read output of {:?}", command))??;
+    Ok((child.wait()?, captured))
+}
+
+/// Copies `input` to `output`, `log` and `captured`. With a `prefix`, the
+/// output is written line by line, each line prefixed.
+fn tee<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    log: &Mutex<Option<fs::File>>,
+    mut captured: Option<&mut Vec<u8>>,
+    prefix: Option<&str>,
+) -> Result<()> {
+    let mut buffer = [0u8; 8192];
+    // The incomplete last line, when prefixing
+    let mut line = vec![];
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match prefix {
+            Some(prefix) => {
+                line.extend_from_slice(&buffer[..read]);
+                while let Some(end) = line.iter().position(|&byte| byte == b'\n') {
+                    let rest = line.split_off(end + 1);
+                    write_prefixed(&mut output, prefix, &line)?;
+                    line = rest;
+                }
+            }
+            None => {
+                output.write_all(&buffer[..read])?;
+                output.flush()?;
+            }
+        }
+        if let Some(log) = log.lock().unwrap().as_mut() {
+            log.write_all(&buffer[..read])?;
+        }
+        if let Some(captured) = captured.as_mut() {
+            captured.extend_from_slice(&buffer[..read]);
+        }
+    }
+    if let (Some(prefix), false) = (prefix, line.is_empty()) {
+        line.push(b'\n');
+        write_prefixed(&mut output, prefix, &line)?;
+    }
+    Ok(())
+}
+
+/// Writes `line` with `prefix` in one go, so lines written by other threads
+/// do not get in between.
+fn write_prefixed<W: Write>(output: &mut W, prefix: &str, line: &[u8]) -> Result<()> {
+    let mut prefixed = prefix.as_bytes().to_vec();
+    prefixed.extend_from_slice(line);
+    output.write_all(&prefixed)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+    use crate::test_build;
+    use std::sync::Arc;
+
+    #[test]
+    fn remote_bundles_share_layout_across_backends() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("my-crate");
+        fs::create_dir_all(source.join("src")).unwrap();
+        fs::write(source.join("src/lib.rs"), "").unwrap();
+        let target_path = source.join("target/some-triple/debug");
+        fs::create_dir_all(&target_path).unwrap();
+        let exe = target_path.join("my_test-1234");
+        fs::write(&exe, "").unwrap();
+
+        let project = Project::new(&Arc::new(Configuration::default()));
+        let runnable = Runnable {
+            id: "my_test-1234".to_string(),
+            exe,
+            source: source.clone(),
+        };
+        let mut build = Build {
+            target_path,
+            ..test_build(vec![runnable.clone()])
+        };
+        build.build_args.bundle_manifest = true;
+
+        let bundle = make_remote_app(&project, &build, &runnable).unwrap();
+        assert_eq!(bundle.bundle_exe.parent(), Some(&*bundle.bundle_dir));
+        assert!(bundle.bundle_dir.join("src/lib.rs").exists());
+        assert!(bundle.bundle_dir.join("test_data").is_dir());
+        let manifest = fs::read_to_string(bundle.bundle_dir.join(BUNDLE_MANIFEST)).unwrap();
+        assert_eq!(
+            manifest,
+            "my_test-1234/_dinghy_my_test-1234\t0\nmy_test-1234/src/lib.rs\t0\n"
+        );
+
+        // android and ssh (default path) remote roots
+        for (base, root) in &[
+            ("/data/local/tmp", "/data/local/tmp/dinghy"),
+            ("/tmp", "/tmp/dinghy"),
+        ] {
+            let remote = bundle.relocate_to(base).unwrap();
+            assert_eq!(remote.bundle_exe.parent(), Some(&*remote.bundle_dir));
+            let command =
+                remote_run_command(&project, &build, &remote, &[], None, &[], &[]).unwrap();
+            assert!(command.starts_with(&format!("cd '{}/my_test-1234' ;", root)));
+            assert!(command.contains(&format!(" {}/my_test-1234/_dinghy_my_test-1234 ", root)));
+        }
+
+        let remote = bundle.replace_prefix_with("/tmp/dinghy").unwrap();
+        let args = vec!["--exact".to_string()];
+        let command = remote_run_command(
+            &project,
+            &build,
+            &remote,
+            &[],
+            Some("/lib/ld-linux.so.3 --library-path /opt/lib"),
+            &[],
+            &args,
+        )
+        .unwrap();
+        assert!(command.ends_with(
+            " /lib/ld-linux.so.3 --library-path /opt/lib /tmp/dinghy/my_test-1234/_dinghy_my_test-1234  --exact"
+        ));
+        assert!(!command.contains("RUST_BACKTRACE"));
+
+        // the runner is for the host only, the exec_prefix for the device
+        build.runner = Some("qemu-arm -L /sysroot".to_string());
+        build.exec_prefix = Some("valgrind".to_string());
+        let command = remote_run_command(&project, &build, &remote, &[], None, &[], &[]).unwrap();
+        assert!(!command.contains("qemu-arm"));
+        let words = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(words.contains(" valgrind /tmp/dinghy/my_test-1234/_dinghy_my_test-1234"));
+        build.exec_prefix = None;
+
+        build.build_args.trace = Some("strace".to_string());
+        let command = remote_run_command(&project, &build, &remote, &[], None, &[], &[]).unwrap();
+        let words = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(words.contains(
+            " strace -f -o '/tmp/dinghy/my_test-1234/dinghy-trace.txt' /tmp/dinghy/my_test-1234/"
+        ));
+    }
+
+    #[test]
+    fn verify_test_data_compares_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("my-crate");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(
+            source.join(".dinghy.toml"),
+            "verified_test_data = [\"model.bin\"]\n",
+        )
+        .unwrap();
+        let bundle_dir = dir.path().join("my_test");
+        fs::create_dir_all(bundle_dir.join("test_data")).unwrap();
+        fs::write(bundle_dir.join("test_data/model.bin"), "weights").unwrap();
+        let bundle = BuildBundle {
+            id: "my_test".to_string(),
+            bundle_dir: bundle_dir.clone(),
+            bundle_exe: bundle_dir.join("_dinghy_my_test"),
+            lib_dir: dir.path().join("overlay"),
+            root_dir: dir.path().to_path_buf(),
+            test_data_dir: None,
+        };
+        let remote = bundle.relocate_to("/tmp").unwrap();
+        let runnable = Runnable {
+            id: "my_test".to_string(),
+            exe: bundle.bundle_exe.clone(),
+            source,
+        };
+        let mut build = Build {
+            target_path: dir.path().join("target"),
+            ..test_build(vec![runnable.clone()])
+        };
+        build.build_args.verify_test_data = true;
+        let project = Project::new(&Arc::new(Configuration::default()));
+        let sum = "9a129038d9a00aed0cf6a7ea059ca50a813449061ab87848cf1a13eafdf33b2c";
+
+        verify_test_data(
+            &project,
+            &build,
+            &runnable,
+            &bundle,
+            &remote,
+            "dev",
+            |command| {
+                assert!(command.starts_with("cd '/tmp/"), "{}", command);
+                assert!(command.ends_with("/my_test/test_data' && sha256sum model.bin"));
+                Ok(format!("{}  model.bin\n", sum))
+            },
+        )
+        .unwrap();
+        let error = verify_test_data(&project, &build, &runnable, &bundle, &remote, "dev", |_| {
+            Ok(format!("{}  model.bin\n", &sum[1..]))
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("model.bin differs"), "{}", error);
+    }
+
+    #[test]
+    fn bundle_namespace_isolates_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let target_path = dir.path().join("target/some-triple/debug");
+        fs::create_dir_all(&target_path).unwrap();
+        let exe = target_path.join("my_test-1234");
+        fs::write(&exe, "").unwrap();
+
+        let mut conf = Configuration::default();
+        conf.bundle_namespace = Some("ci-job-42".to_string());
+        let project = Project::new(&Arc::new(conf));
+        let runnable = Runnable {
+            id: "my_test-1234".to_string(),
+            exe,
+            source: dir.path().to_path_buf(),
+        };
+        let build = Build {
+            target_path: target_path.clone(),
+            ..test_build(vec![runnable.clone()])
+        };
+
+        let bundle = make_remote_app(&project, &build, &runnable).unwrap();
+        assert_eq!(bundle.root_dir, target_path.join("ci-job-42"));
+        let remote = bundle.relocate_to("/data/local/tmp").unwrap();
+        assert_eq!(
+            remote.bundle_exe,
+            Path::new("/data/local/tmp/ci-job-42/my_test-1234/_dinghy_my_test-1234")
+        );
+        assert_eq!(
+            remote.lib_dir,
+            Path::new("/data/local/tmp/ci-job-42/overlay")
+        );
+
+        let mut conf = Configuration::default();
+        conf.bundle_namespace = Some("../escape".to_string());
+        assert!(Project::new(&Arc::new(conf)).bundle_namespace().is_err());
+    }
+
+    #[test]
+    fn shared_test_data_is_linked_from_the_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("my-crate");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join(".dinghy.toml"), "shared_test_data = true\n").unwrap();
+        let target_path = source.join("target/some-triple/debug");
+        fs::create_dir_all(&target_path).unwrap();
+        let exe = target_path.join("my_test-1234");
+        fs::write(&exe, "").unwrap();
+
+        let project = Project::new(&Arc::new(Configuration::default()));
+        let runnable = Runnable {
+            id: "my_test-1234".to_string(),
+            exe,
+            source,
+        };
+        let build = Build {
+            target_path,
+            ..test_build(vec![runnable.clone()])
+        };
+
+        let bundle = make_remote_app(&project, &build, &runnable).unwrap();
+        let shared = bundle.root_dir.join("shared_test_data/my-crate/test_data");
+        assert_eq!(bundle.test_data_dir.as_ref(), Some(&shared));
+        assert_eq!(
+            fs::read_link(bundle.bundle_dir.join("test_data")).unwrap(),
+            Path::new("../shared_test_data/my-crate/test_data")
+        );
+        assert!(bundle.bundle_dir.join("test_data").is_dir());
+
+        let remote = bundle.replace_prefix_with("/tmp/dinghy").unwrap();
+        assert_eq!(
+            remote.test_data_dir,
+            Some(PathBuf::from(
+                "/tmp/dinghy/shared_test_data/my-crate/test_data"
+            ))
+        );
+
+        let installed = Mutex::new(HashSet::new());
+        let mut copies = vec![];
+        for _ in 0..2 {
+            install_shared_test_data(&bundle, &remote, &installed, |local, remote| {
+                copies.push((local.to_path_buf(), remote.to_path_buf()));
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            copies,
+            vec![(shared, remote.test_data_dir.clone().unwrap())]
+        );
+    }
+
+    #[test]
+    fn backtraces_are_on_unless_set_elsewhere() {
+        let mut envs = vec![];
+        add_backtrace_env(&mut envs, false, false);
+        assert_eq!(envs, vec!["RUST_BACKTRACE=1"]);
+
+        let mut envs = vec!["RUST_BACKTRACE=full".to_string()];
+        add_backtrace_env(&mut envs, false, false);
+        assert_eq!(envs, vec!["RUST_BACKTRACE=full"]);
+
+        let mut envs = vec![];
+        add_backtrace_env(&mut envs, false, true);
+        assert!(envs.is_empty());
+
+        // The last one wins when the runnable starts
+        let mut envs = vec!["RUST_BACKTRACE=full".to_string()];
+        add_backtrace_env(&mut envs, true, true);
+        assert_eq!(envs, vec!["RUST_BACKTRACE=full", "RUST_BACKTRACE=0"]);
+    }
+
+    #[test]
+    fn gdbserver_ports_sit_above_the_lldb_port() {
+        let ports = lldb_gdbserver_ports(1234).unwrap();
+        assert_eq!(ports, 1235..1239);
+        assert_eq!(
+            lldb_platform_command("lldb-server", "/tmp/lldb.port", &ports),
+            "lldb-server platform --server --listen 127.0.0.1:0 --socket-file /tmp/lldb.port \
+             --min-gdbserver-port 1235 --max-gdbserver-port 1239"
+        );
+        assert!(lldb_gdbserver_ports(65533).is_err());
+    }
+
+    #[test]
+    fn tee_prefixes_each_line() {
+        let mut output = vec![];
+        let mut captured = vec![];
+        tee(
+            &b"first\nsecond"[..],
+            &mut output,
+            &Mutex::new(None),
+            Some(&mut captured),
+            Some("[raspi] "),
+        )
+        .unwrap();
+        assert_eq!(output, b"[raspi] first\n[raspi] second\n");
+        assert_eq!(captured, b"first\nsecond");
+    }
+
+    #[test]
+    fn log_files_tee_output_per_runnable() {
+        let dir = tempfile::tempdir().unwrap();
+        let runnable = |id: &str| Runnable {
+            id: id.to_string(),
+            exe: dir.path().join(id),
+            source: dir.path().to_path_buf(),
+        };
+        let mut build = Build {
+            target_path: dir.path().to_path_buf(),
+            ..test_build(vec![])
+        };
+        build.build_args.log_file = Some(dir.path().join("{device}-{runnable}.log"));
+        let log_path = log_file_path(&build, "raspi", &runnable("a")).unwrap();
+        assert_eq!(log_path, dir.path().join("raspi-a.log"));
+
+        for _ in 0..2 {
+            let log = open_log_file(&build, "raspi", &runnable("a")).unwrap();
+            let (status, stdout) =
+                run_and_tee(Command::new("sh").args(["-c", "echo out"]), log).unwrap();
+            assert!(status.success());
+            assert_eq!(stdout, b"out\n");
+        }
+        let log = open_log_file(&build, "raspi", &runnable("b")).unwrap();
+        run_and_tee(Command::new("sh").args(["-c", "echo err >&2"]), log).unwrap();
+
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "out\nout\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("raspi-b.log")).unwrap(),
+            "err\n"
+        );
+    }
+}
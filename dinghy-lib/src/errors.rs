@@ -1,4 +1,60 @@
 pub use anyhow::*;
+use std::fmt;
+
+/// Failures callers may want to react to. They travel inside `anyhow::Error`,
+/// match them with `error.downcast_ref::<DinghyError>()`.
+#[derive(Debug)]
+pub enum DinghyError {
+    DeviceNotFound(Option<String>),
+    BuildFailed,
+    /// A command dinghy runs to drive a device (adb, ssh, scp...) failed.
+    CommandFailed {
+        command: String,
+        code: Option<i32>,
+    },
+    InstallFailed(String),
+    PackagesFilteredOut {
+        packages: Vec<String>,
+        platform: String,
+    },
+    RemoteCommandFailed {
+        code: Option<i32>,
+    },
+}
+
+impl fmt::Display for DinghyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DinghyError::DeviceNotFound(None) => write!(f, "No device found"),
+            DinghyError::DeviceNotFound(Some(hint)) => {
+                write!(f, "No devices found for name hint `{}'", hint)
+            }
+            DinghyError::BuildFailed => write!(f, "Build failed"),
+            DinghyError::CommandFailed {
+                command,
+                code: Some(code),
+            } => write!(f, "`{}` failed with exit code {}", command, code),
+            DinghyError::CommandFailed {
+                command,
+                code: None,
+            } => write!(f, "`{}` was killed by a signal", command),
+            DinghyError::InstallFailed(device_id) => {
+                write!(f, "Failed to install on device {}", device_id)
+            }
+            DinghyError::PackagesFilteredOut { packages, platform } => write!(
+                f,
+                "packages {:?} are filtered out on platform {}",
+                packages, platform
+            ),
+            DinghyError::RemoteCommandFailed { code: Some(code) } => {
+                write!(f, "Test failed 🐛 (exit code {})", code)
+            }
+            DinghyError::RemoteCommandFailed { code: None } => write!(f, "Test failed 🐛"),
+        }
+    }
+}
+
+impl std::error::Error for DinghyError {}
 
 /*
 
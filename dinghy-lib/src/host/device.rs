@@ -1,16 +1,23 @@
 use crate::compiler::Compiler;
+use crate::errors::*;
+use crate::platform::regular_platform::RegularPlatform;
 use crate::project::Project;
 use crate::Build;
 use crate::BuildBundle;
 use crate::Device;
 use crate::DeviceCompatibility;
-use crate::Result;
+use crate::DeviceKind;
+use crate::Platform;
+use cargo::core::compiler::CompileKind;
+use dinghy_build::build_env::envify;
 use dinghy_build::build_env::set_env;
-use itertools::Itertools;
 use std::fmt;
 use std::fmt::Formatter;
 use std::fmt::{Debug, Display};
+use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use crate::host::HostPlatform;
 
 pub struct HostDevice {
@@ -27,7 +34,7 @@ impl HostDevice {
     }
 
     fn install_all_apps(&self, project: &Project, build: &Build) -> Result<Vec<BuildBundle>> {
-        let root_dir = build.target_path.join("dinghy");
+        let root_dir = build.target_path.join(project.bundle_namespace()?);
         let bundle_libs_path = build.target_path.clone();
 
         let mut build_bundles = vec![];
@@ -43,10 +50,41 @@ impl HostDevice {
                 bundle_exe: bundle_exe_path.to_path_buf(),
                 lib_dir: bundle_libs_path.to_path_buf(),
                 root_dir: root_dir.clone(),
+                test_data_dir: None,
             });
         }
         Ok(build_bundles)
     }
+
+    /// Runs binaries built for another target through the platform runner
+    /// (e.g. qemu-user), without going through cargo.
+    fn run_emulated(&self, project: &Project, build: &Build, args: &[String]) -> Result<()> {
+        let runner = build
+            .runner
+            .as_ref()
+            .ok_or_else(|| anyhow!("A runner is needed to run on host"))?;
+        let mut runner = runner.split_whitespace();
+        let program = runner.next().ok_or_else(|| anyhow!("Empty runner"))?;
+        for runnable in &build.runnables {
+            let mut command = Command::new(program);
+            command
+                .args(runner.clone())
+                .arg(&runnable.exe)
+                .args(args)
+                .current_dir(&runnable.source);
+            if project.sets_dinghy_env() {
+                command.env("DINGHY", "1");
+            }
+            debug!("Running {:?}", command);
+            let status = command.status()?;
+            if !status.success() {
+                bail!(DinghyError::RemoteCommandFailed {
+                    code: status.code()
+                })
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Device for HostDevice {
@@ -69,6 +107,10 @@ impl Device for HostDevice {
         "HOST"
     }
 
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Host
+    }
+
     fn name(&self) -> &str {
         "host device"
     }
@@ -79,21 +121,39 @@ impl Device for HostDevice {
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>> {
-        for (env_key, env_value) in envs.iter().tuples() {
-            set_env(env_key, env_value);
+    ) -> Result<(Vec<BuildBundle>, Duration)> {
+        for env in envs {
+            match env.split_once('=') {
+                Some((env_key, env_value)) => set_env(env_key, env_value),
+                None => bail!("Invalid environment variable '{}', expected KEY=VALUE", env),
+            }
         }
+        let install_start = Instant::now();
         let build_bundles = self.install_all_apps(project, build)?;
+        let install_time = install_start.elapsed();
         let args = args
             .iter()
             .map(|arg| Ok(shellexpand::full(arg)?.to_string()))
             .collect::<Result<Vec<_>>>()?;
         debug!("Arguments expanded to: {:?}", args);
+        if build.target != CompileKind::Host {
+            self.run_emulated(project, build, &args)?;
+            return Ok((build_bundles, install_time));
+        }
+        if let Some(runner) = &build.runner {
+            set_env(
+                format!(
+                    "CARGO_TARGET_{}_RUNNER",
+                    envify(self.platform.rustc_triple())
+                ),
+                runner,
+            );
+        }
         self.compiler.run(&self.platform, &build.build_args, &*args)?;
-        Ok(build_bundles)
+        Ok((build_bundles, install_time))
     }
 
-    fn start_remote_lldb(&self) -> Result<String> {
+    fn start_remote_lldb(&self, _local_port: Option<u16>) -> Result<String> {
         unimplemented!()
     }
 }
@@ -114,4 +174,8 @@ impl DeviceCompatibility for HostDevice {
     fn is_compatible_with_host_platform(&self, _platform: &super::HostPlatform) -> bool {
         true
     }
+
+    fn is_compatible_with_regular_platform(&self, platform: &RegularPlatform) -> bool {
+        platform.configuration.runner.is_some()
+    }
 }
@@ -42,12 +42,15 @@ impl Debug for HostPlatform {
 
 impl Platform for HostPlatform {
     fn build(&self, project: &Project, build_args: &BuildArgs) -> Result<Build> {
-        // Set custom env variables specific to the platform
-        set_all_env(&self.configuration.env());
+        self.setup_env(project, build_args)?;
 
-        Overlayer::overlay(&self.configuration, self, project, "/")?;
+        let mut build = self.compiler.build(self, build_args)?;
+        build.runner = self.configuration.runner.clone();
+        Ok(build)
+    }
 
-        self.compiler.build(self, build_args)
+    fn configuration(&self) -> &PlatformConfiguration {
+        &self.configuration
     }
 
     fn id(&self) -> String {
@@ -62,6 +65,14 @@ impl Platform for HostPlatform {
         CompileKind::Host
     }
 
+    fn setup_env(&self, project: &Project, _build_args: &BuildArgs) -> Result<()> {
+        // Set custom env variables, the platform ones overriding the global ones
+        set_all_env(&project.conf.env.iter().collect::<Vec<_>>());
+        set_all_env(&self.configuration.env());
+
+        Overlayer::overlay(&self.configuration, self, project, "/")
+    }
+
     fn sysroot(&self) -> Result<Option<std::path::PathBuf>> {
         Ok(Some(std::path::PathBuf::from("/")))
     }
@@ -71,13 +82,17 @@ impl Platform for HostPlatform {
     }
 
     fn rustc_triple(&self) -> &str {
-        std::env!("TARGET")
+        crate::HOST_TRIPLE
     }
 
-    fn strip(&self, build: &Build) -> Result<()> {
+    fn strip(&self, build: &Build, debug_files: bool) -> Result<()> {
         for runnable in &build.runnables {
             info!("Stripping {}", runnable.exe.display());
-            platform::strip_runnable(runnable, Command::new("strip"))?;
+            platform::strip_runnable(
+                runnable,
+                Command::new("strip"),
+                Some("objcopy").filter(|_| debug_files),
+            )?;
         }
         Ok(())
     }
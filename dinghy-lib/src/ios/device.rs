@@ -1,13 +1,16 @@
 use super::mobiledevice_sys::*;
 use super::xcode;
+use crate::config::IosDeviceFamily;
 use crate::device::make_remote_app_with_name;
 use crate::errors::*;
 use crate::ios::IosPlatform;
 use crate::project::Project;
+use crate::utils::path_to_str;
 use crate::Build;
 use crate::BuildBundle;
 use crate::Device;
 use crate::DeviceCompatibility;
+use crate::DeviceKind;
 use crate::Runnable;
 use core_foundation::array::CFArray;
 use core_foundation::base::{CFType, CFTypeRef, ItemRef, TCFType};
@@ -28,14 +31,18 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
+
+const DEFAULT_MIN_OS_VERSION: &str = "12.0";
 
 #[derive(Clone, Debug)]
 pub struct IosDevice {
     pub id: String,
     pub name: String,
-    ptr: *const am_device,
+    ptr: Arc<Mutex<AmDevice>>,
     arch_cpu: &'static str,
     rustc_triple: String,
 }
@@ -47,7 +54,14 @@ pub struct IosSimDevice {
     pub os: String,
 }
 
-unsafe impl Send for IosDevice {}
+/// MobileDevice handle of a device. `IosDevice` keeps it behind a mutex, so
+/// only one thread at a time talks to the device through it.
+#[derive(Debug)]
+struct AmDevice(*const am_device);
+
+// SAFETY: the handle belongs to MobileDevice, not to the thread that found the
+// device, and it is only used while holding the mutex of its `IosDevice`.
+unsafe impl Send for AmDevice {}
 
 impl IosDevice {
     pub fn new(ptr: *const am_device) -> Result<IosDevice> {
@@ -66,7 +80,7 @@ impl IosDevice {
             bail!("unexpected id format")
         };
         Ok(IosDevice {
-            ptr: ptr,
+            ptr: Arc::new(Mutex::new(AmDevice(ptr))),
             name: name,
             id: id,
             arch_cpu: cpu.into(),
@@ -80,7 +94,7 @@ impl IosDevice {
         build: &Build,
         runnable: &Runnable,
     ) -> Result<BuildBundle> {
-        let signing = xcode::look_for_signature_settings(&self.id)?
+        let signing = xcode::look_for_signature_settings(&self.id, &project.conf)?
             .pop()
             .ok_or_else(|| anyhow!("no signing identity found"))?;
         let app_id = signing
@@ -91,10 +105,15 @@ impl IosDevice {
 
         let build_bundle = make_ios_app(project, build, runnable, &app_id)?;
 
-        super::xcode::sign_app(&build_bundle, &signing)?;
+        super::xcode::sign_app(&build_bundle, &signing, &project.conf)?;
         Ok(build_bundle)
     }
 
+    /// The MobileDevice handle, locked for the duration of a call.
+    fn device(&self) -> Result<MutexGuard<AmDevice>> {
+        self.ptr.lock().map_err(|_| anyhow!("poisoned lock"))
+    }
+
     fn install_app(
         &self,
         project: &Project,
@@ -102,7 +121,11 @@ impl IosDevice {
         runnable: &Runnable,
     ) -> Result<BuildBundle> {
         let build_bundle = self.make_app(project, build, runnable)?;
-        install_app(self.ptr, &build_bundle.bundle_dir)?;
+        if devicectl_available() {
+            devicectl_install(&self.id, &build_bundle.bundle_dir)?;
+        } else {
+            install_app(self.device()?.0, &build_bundle.bundle_dir)?;
+        }
         Ok(build_bundle)
     }
 }
@@ -125,9 +148,9 @@ impl Device for IosDevice {
             .next()
             .ok_or_else(|| anyhow!("No executable compiled"))?;
         let build_bundle = self.install_app(project, build, runnable)?;
-        let lldb_proxy = self.start_remote_lldb()?;
+        let lldb_proxy = self.start_remote_lldb(None)?;
         run_remote(
-            self.ptr,
+            self.device()?.0,
             &lldb_proxy,
             &build_bundle.bundle_dir,
             args,
@@ -141,6 +164,10 @@ impl Device for IosDevice {
         &self.id
     }
 
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Ios
+    }
+
     fn name(&self) -> &str {
         &self.name
     }
@@ -151,33 +178,47 @@ impl Device for IosDevice {
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>> {
+    ) -> Result<(Vec<BuildBundle>, Duration)> {
         let mut build_bundles = vec![];
+        let mut install_time = Duration::ZERO;
         for runnable in &build.runnables {
+            let install_start = Instant::now();
             let build_bundle = self.install_app(&project, &build, &runnable)?;
-            let lldb_proxy = self.start_remote_lldb()?;
-            run_remote(
-                self.ptr,
-                &lldb_proxy,
-                &build_bundle.bundle_dir,
-                args,
-                envs,
-                false,
-            )?;
+            install_time += install_start.elapsed();
+            if devicectl_available() {
+                devicectl_launch(&self.id, &build_bundle.bundle_dir, args, envs)?;
+            } else {
+                let lldb_proxy = self.start_remote_lldb(None)?;
+                run_remote(
+                    self.device()?.0,
+                    &lldb_proxy,
+                    &build_bundle.bundle_dir,
+                    args,
+                    envs,
+                    false,
+                )?;
+            }
             build_bundles.push(build_bundle)
         }
-        Ok(build_bundles)
+        Ok((build_bundles, install_time))
     }
 
-    fn start_remote_lldb(&self) -> Result<String> {
-        let _ = ensure_session(self.ptr);
-        let fd = start_remote_debug_server(self.ptr)?;
+    fn start_remote_lldb(&self, local_port: Option<u16>) -> Result<String> {
+        let device = self.device()?;
+        let _ = ensure_session(device.0);
+        let fd = start_remote_debug_server(device.0)?;
+        drop(device);
         debug!("start local lldb proxy");
-        let proxy = start_lldb_proxy(fd)?;
+        let proxy = start_lldb_proxy(fd, local_port.unwrap_or(0))?;
         let url = format!("localhost:{}", proxy);
         debug!("started lldb proxy {}", url);
         Ok(url)
     }
+
+    fn stop_remote_lldb(&self) -> Result<()> {
+        // The debugserver goes away with the proxy socket when dinghy exits.
+        Ok(())
+    }
 }
 
 impl IosSimDevice {
@@ -242,7 +283,9 @@ impl Device for IosSimDevice {
                 .output()?
                 .stdout,
         )?;
-        launch_lldb_simulator(&self, &install_path, args, envs, true)?;
+        let envs = simulator_envs(&build_bundle, envs)?;
+        let envs = envs.iter().map(|it| &it[..]).collect::<Vec<_>>();
+        launch_lldb_simulator(&self, &install_path, args, &envs, true)?;
         Ok(build_bundle)
     }
 
@@ -250,6 +293,10 @@ impl Device for IosSimDevice {
         &self.id
     }
 
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Ios
+    }
+
     fn name(&self) -> &str {
         &self.name
     }
@@ -260,17 +307,22 @@ impl Device for IosSimDevice {
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>> {
+    ) -> Result<(Vec<BuildBundle>, Duration)> {
         let mut build_bundles = vec![];
+        let mut install_time = Duration::ZERO;
         for runnable in &build.runnables {
+            let install_start = Instant::now();
             let build_bundle = self.install_app(&project, &build, &runnable)?;
-            launch_app(&self, args, envs)?;
+            install_time += install_start.elapsed();
+            let envs = simulator_envs(&build_bundle, envs)?;
+            let envs = envs.iter().map(|it| &it[..]).collect::<Vec<_>>();
+            launch_app(&self, args, &envs)?;
             build_bundles.push(build_bundle);
         }
-        Ok(build_bundles)
+        Ok((build_bundles, install_time))
     }
 
-    fn start_remote_lldb(&self) -> Result<String> {
+    fn start_remote_lldb(&self, _local_port: Option<u16>) -> Result<String> {
         unimplemented!()
     }
 }
@@ -300,6 +352,10 @@ impl Display for IosSimDevice {
 }
 
 impl DeviceCompatibility for IosDevice {
+    fn is_ios_simulator(&self) -> Option<bool> {
+        Some(false)
+    }
+
     fn is_compatible_with_ios_platform(&self, platform: &IosPlatform) -> bool {
         if platform.sim {
             return false;
@@ -313,6 +369,10 @@ impl DeviceCompatibility for IosDevice {
 }
 
 impl DeviceCompatibility for IosSimDevice {
+    fn is_ios_simulator(&self) -> Option<bool> {
+        Some(true)
+    }
+
     fn is_compatible_with_ios_platform(&self, platform: &IosPlatform) -> bool {
         platform.sim && platform.toolchain.rustc_triple == "x86_64-apple-ios"
     }
@@ -504,10 +564,41 @@ fn make_ios_app(
         .split(" ")
         .last()
         .ok_or_else(|| anyhow!("empty magic"))?;
-    xcode::add_plist_to_app(&build_bundle, target, app_id)?;
+    xcode::add_plist_to_app(
+        &build_bundle,
+        target,
+        app_id,
+        &crate_version(&runnable.source),
+        project
+            .conf
+            .ios_min_os_version
+            .as_deref()
+            .unwrap_or(DEFAULT_MIN_OS_VERSION),
+        project
+            .conf
+            .ios_device_family
+            .as_deref()
+            .unwrap_or(&[IosDeviceFamily::Iphone, IosDeviceFamily::Ipad]),
+    )?;
     Ok(build_bundle)
 }
 
+/// Version of the crate in `source`, without pre-release or build metadata
+/// which bundle versions do not accept.
+fn crate_version(source: &Path) -> String {
+    let manifest = fs::read_to_string(source.join("Cargo.toml"))
+        .ok()
+        .and_then(|it| it.parse::<toml::Value>().ok());
+    manifest
+        .as_ref()
+        .and_then(|it| it.get("package"))
+        .and_then(|it| it.get("version"))
+        .and_then(|it| it.as_str())
+        .and_then(|it| it.split(|c| c == '-' || c == '+').next())
+        .unwrap_or("1.0")
+        .to_string()
+}
+
 struct Session(*const am_device);
 
 fn ensure_session(dev: *const am_device) -> Result<Session> {
@@ -545,6 +636,87 @@ impl Drop for Session {
     }
 }
 
+/// Whether `xcrun devicectl`, which replaces the older device tools in recent
+/// Xcode versions, is available. It is checked once.
+fn devicectl_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let available = process::Command::new("xcrun")
+            .args(["devicectl", "--version"])
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        debug!("devicectl available: {}", available);
+        available
+    })
+}
+
+fn devicectl_install(device_id: &str, app: &Path) -> Result<()> {
+    let mut command = process::Command::new("xcrun");
+    command
+        .args([
+            "devicectl",
+            "device",
+            "install",
+            "app",
+            "--device",
+            device_id,
+        ])
+        .arg(app);
+    debug!("Running {:?}", command);
+    if !command.status()?.success() {
+        bail!(
+            "devicectl failed to install {} on {}",
+            app.display(),
+            device_id
+        )
+    }
+    Ok(())
+}
+
+/// Launches the installed app of the `app` bundle with devicectl, printing its
+/// output until it exits.
+fn devicectl_launch(device_id: &str, app: &Path, args: &[&str], envs: &[&str]) -> Result<()> {
+    let plist = plist::Value::from_file(app.join("Info.plist"))?;
+    let bundle_id = plist
+        .as_dictionary()
+        .and_then(|dict| dict.get("CFBundleIdentifier"))
+        .and_then(|bundle_id| bundle_id.as_string())
+        .ok_or_else(|| anyhow!("No CFBundleIdentifier in {}", app.display()))?;
+    let mut command = process::Command::new("xcrun");
+    command.args([
+        "devicectl",
+        "device",
+        "process",
+        "launch",
+        "--device",
+        device_id,
+        "--terminate-existing",
+        "--console",
+    ]);
+    if !envs.is_empty() {
+        let mut variables = ::json::JsonValue::new_object();
+        for env in envs {
+            let (key, value) = env
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid env var {}, expected KEY=VALUE", env))?;
+            variables[key] = value.into();
+        }
+        command.arg("--environment-variables").arg(variables.dump());
+    }
+    command.arg(bundle_id).args(args);
+    debug!("Running {:?}", command);
+    let status = command.status()?;
+    if !status.success() {
+        bail!(DinghyError::RemoteCommandFailed {
+            code: status.code()
+        })
+    }
+    Ok(())
+}
+
 pub fn install_app<P: AsRef<Path>>(dev: *const am_device, app: P) -> Result<()> {
     unsafe {
         let _session = ensure_session(dev)?;
@@ -599,12 +771,12 @@ fn start_remote_debug_server(dev: *const am_device) -> Result<c_int> {
     }
 }
 
-fn start_lldb_proxy(fd: c_int) -> Result<u16> {
+fn start_lldb_proxy(fd: c_int, port: u16) -> Result<u16> {
     use std::io::{Read, Write};
     use std::net::{TcpListener, TcpStream};
     use std::os::unix::io::FromRawFd;
     let device = unsafe { TcpStream::from_raw_fd(fd) };
-    let proxy = TcpListener::bind("127.0.0.1:0")?;
+    let proxy = TcpListener::bind(("127.0.0.1", port))?;
     let addr = proxy.local_addr()?;
     device.set_nonblocking(true)?;
     thread::spawn(move || {
@@ -685,6 +857,10 @@ fn launch_lldb_device<P: AsRef<Path>, P2: AsRef<Path>>(
             script,
             "command script add -s synchronous -f helpers.start start"
         )?;
+        writeln!(
+            script,
+            "command script add -s synchronous -f helpers.launch launch"
+        )?;
 
         writeln!(script, "connect connect://{}", proxy)?;
         writeln!(
@@ -692,7 +868,11 @@ fn launch_lldb_device<P: AsRef<Path>, P2: AsRef<Path>>(
             "set_remote_path {}",
             remote.as_ref().to_str().unwrap()
         )?;
-        if !debugger {
+        if debugger {
+            // Stop at entry so breakpoints can be set before resuming with
+            // `continue`, lldb then hands over its prompt.
+            writeln!(script, "launch {}", args.join(" "))?;
+        } else {
             writeln!(script, "start {}", args.join(" "))?;
             writeln!(script, "quit")?;
         }
@@ -710,7 +890,18 @@ fn launch_lldb_device<P: AsRef<Path>, P2: AsRef<Path>>(
     }
 }
 
-fn launch_app(dev: &IosSimDevice, app_args: &[&str], _envs: &[&str]) -> Result<()> {
+/// `envs` with the bundled dynamic libraries in `DYLD_LIBRARY_PATH`. The
+/// simulator runs on the host, so it loads them from the host bundle.
+fn simulator_envs(build_bundle: &BuildBundle, envs: &[&str]) -> Result<Vec<String>> {
+    let mut simulator_envs = vec![format!(
+        "DYLD_LIBRARY_PATH={}",
+        path_to_str(&build_bundle.lib_dir)?
+    )];
+    simulator_envs.extend(envs.iter().map(|it| it.to_string()));
+    Ok(simulator_envs)
+}
+
+fn launch_app(dev: &IosSimDevice, app_args: &[&str], envs: &[&str]) -> Result<()> {
     use std::io::Write;
     let dir = ::tempdir::TempDir::new("mobiledevice-rs-lldb")?;
     let tmppath = dir.path();
@@ -725,11 +916,34 @@ fn launch_app(dev: &IosSimDevice, app_args: &[&str], _envs: &[&str]) -> Result<(
         .join("stdout")
         .to_string_lossy()
         .into_owned();
+    let stderr = Path::new(&install_path)
+        .join("stderr")
+        .to_string_lossy()
+        .into_owned();
     let stdout_param = &format!("--stdout={}", stdout);
-    let mut xcrun_args: Vec<&str> = vec!["simctl", "launch", "-w", stdout_param, &dev.id, "Dinghy"];
+    let stderr_param = &format!("--stderr={}", stderr);
+    let mut xcrun_args: Vec<&str> = vec![
+        "simctl",
+        "launch",
+        "-w",
+        "--terminate-running-process",
+        stdout_param,
+        stderr_param,
+        &dev.id,
+        "Dinghy",
+    ];
     xcrun_args.extend(app_args);
     debug!("Launching app via xcrun using args: {:?}", xcrun_args);
-    let launch_output = process::Command::new("xcrun").args(&xcrun_args).output()?;
+    // simctl forwards SIMCTL_CHILD_FOO=bar to the launched app as FOO=bar
+    let mut command = process::Command::new("xcrun");
+    command.args(&xcrun_args).env("SIMCTL_CHILD_DINGHY", "1");
+    for env in envs {
+        let mut kv = env.splitn(2, '=');
+        let key = kv.next().ok_or_else(|| anyhow!("Wrong env spec"))?;
+        let value = kv.next().ok_or_else(|| anyhow!("Wrong env spec"))?;
+        command.env(format!("SIMCTL_CHILD_{}", key), value);
+    }
+    let launch_output = command.output()?;
     let launch_output = String::from_utf8_lossy(&launch_output.stdout);
 
     // Output from the launch command should be "Dinghy: $PID" which is after the 8th character.
@@ -749,6 +963,9 @@ fn launch_app(dev: &IosSimDevice, app_args: &[&str], _envs: &[&str]) -> Result<(
         .output()?;
     let test_contents = std::fs::read_to_string(stdout)?;
     println!("{}", test_contents);
+    if let Ok(test_errors) = std::fs::read_to_string(stderr) {
+        eprint!("{}", test_errors);
+    }
 
     let output: String = String::from_utf8_lossy(&output.stdout).to_string();
     debug!("LLDB OUTPUT: {}", output);
@@ -779,20 +996,22 @@ fn launch_app(dev: &IosSimDevice, app_args: &[&str], _envs: &[&str]) -> Result<(
     if let Some(exit_status_line) = exit_status_line {
         let words: Vec<&str> = exit_status_line.split_whitespace().rev().collect();
         if let Some(exit_status) = words.get(1) {
-            let exit_status = exit_status.parse::<u32>()?;
+            let exit_status = exit_status.parse::<i32>()?;
             if exit_status == 0 {
                 Ok(())
             } else {
-                panic!("Non-zero exit code from lldb: {}", exit_status);
+                bail!(DinghyError::RemoteCommandFailed {
+                    code: Some(exit_status)
+                })
             }
         } else {
-            panic!(
+            bail!(
                 "Failed to parse lldb exit line for an exit status. {:?}",
                 words
-            );
+            )
         }
     } else {
-        panic!("Failed to get the exit status line from lldb: {:?}", lines);
+        bail!("Failed to get the exit status line from lldb: {:?}", lines)
     }
 }
 
@@ -12,6 +12,15 @@ def set_remote_path(debugger, command, result, internal_dict):
     error = lldb.SBError()
     lldb.target.modules[0].SetPlatformFileSpec(lldb.SBFileSpec(device_app))
 
+def launch(debugger, command, result, internal_dict):
+    error = lldb.SBError()
+    info = lldb.SBLaunchInfo(shlex.split(command))
+    info.SetEnvironmentEntries(["ENV_VAR_PLACEHOLDER"], True)
+    info.SetLaunchFlags(info.GetLaunchFlags() | lldb.eLaunchFlagStopAtEntry)
+    lldb.target.Launch(info, error)
+    if not error.Success():
+        print(str(error))
+
 def start(debugger, command, result, internal_dict):
     error = lldb.SBError()
     info = lldb.SBLaunchInfo(shlex.split(command))
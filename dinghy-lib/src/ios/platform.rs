@@ -9,7 +9,7 @@ use crate::BuildArgs;
 use crate::Device;
 use crate::Platform;
 use cargo::core::compiler::{CompileKind, CompileTarget};
-use dinghy_build::build_env::set_env;
+use dinghy_build::build_env::{set_all_env, set_env};
 use std::fmt::{Debug, Display, Formatter};
 use std::process;
 use std::sync::Arc;
@@ -61,24 +61,42 @@ impl IosPlatform {
 
 impl Platform for IosPlatform {
     fn build(&self, project: &Project, build_args: &BuildArgs) -> Result<Build> {
-        let sysroot = self.sysroot_path()?;
-        Overlayer::overlay(&self.configuration, self, project, &self.sysroot_path()?)?;
-        self.toolchain.setup_cc(self.id().as_str(), "gcc")?;
-        set_env("TARGET_SYSROOT", &sysroot);
-        self.toolchain
-            .setup_linker(&self.id(), &format!("cc -isysroot {}", sysroot))?;
-        dbg!(&self.toolchain);
-        self.toolchain.setup_pkg_config()?;
+        self.setup_env(project, build_args)?;
 
         self.compiler.build(self, build_args)
     }
 
+    fn configuration(&self) -> &PlatformConfiguration {
+        &self.configuration
+    }
+
     fn id(&self) -> String {
         self.id.to_string()
     }
 
     fn is_compatible_with(&self, device: &dyn Device) -> bool {
-        device.is_compatible_with_ios_platform(self)
+        device.is_ios_simulator() == Some(self.sim) && device.is_compatible_with_ios_platform(self)
+    }
+
+    fn incompatibility_with(&self, device: &dyn Device) -> Option<String> {
+        match device.is_ios_simulator() {
+            Some(false) if self.sim => Some(format!(
+                "simulator platform {} selected but device {} is physical",
+                self.id,
+                device.id()
+            )),
+            Some(true) if !self.sim => Some(format!(
+                "device platform {} selected but device {} is a simulator",
+                self.id,
+                device.id()
+            )),
+            _ if self.is_compatible_with(device) => None,
+            _ => Some(format!(
+                "platform {} is not compatible with device {}",
+                self.id,
+                device.id()
+            )),
+        }
     }
 
     fn is_host(&self) -> bool {
@@ -93,15 +111,31 @@ impl Platform for IosPlatform {
         CompileKind::Target(CompileTarget::new(self.rustc_triple()).unwrap())
     }
 
+    fn setup_env(&self, project: &Project, _build_args: &BuildArgs) -> Result<()> {
+        // Set custom env variables, the platform ones overriding the global ones
+        set_all_env(&project.conf.env.iter().collect::<Vec<_>>());
+        set_all_env(&self.configuration.env());
+        let sysroot = self.sysroot_path()?;
+        Overlayer::overlay(&self.configuration, self, project, &sysroot)?;
+        self.toolchain.setup_cc(self.id().as_str(), "gcc")?;
+        set_env("TARGET_SYSROOT", &sysroot);
+        self.toolchain
+            .setup_linker(&self.id(), &format!("cc -isysroot {}", sysroot))?;
+        self.toolchain.setup_pkg_config()
+    }
+
     fn sysroot(&self) -> Result<Option<std::path::PathBuf>> {
         self.sysroot_path().map(|s| Some(s.into()))
     }
 
-    fn strip(&self, build: &Build) -> Result<()> {
+    fn strip(&self, build: &Build, debug_files: bool) -> Result<()> {
+        if debug_files {
+            warn!("Debug symbol files are not supported on iOS, use dsymutil");
+        }
         for runnable in &build.runnables {
             let mut command = ::std::process::Command::new("xcrun");
             command.arg("strip");
-            crate::platform::strip_runnable(runnable, command)?;
+            crate::platform::strip_runnable(runnable, command, None)?;
         }
         Ok(())
     }
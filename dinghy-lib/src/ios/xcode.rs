@@ -1,11 +1,20 @@
 use super::{SignatureSettings, SigningIdentity};
+use crate::config::Configuration;
+use crate::config::IosDeviceFamily;
 use crate::errors::*;
 use std::io::Write;
 use std::{fs, io, process};
 
 use crate::BuildBundle;
 
-pub fn add_plist_to_app(bundle: &BuildBundle, arch: &str, app_bundle_id: &str) -> Result<()> {
+pub fn add_plist_to_app(
+    bundle: &BuildBundle,
+    arch: &str,
+    app_bundle_id: &str,
+    version: &str,
+    min_os_version: &str,
+    device_families: &[IosDeviceFamily],
+) -> Result<()> {
     let mut plist = fs::File::create(bundle.bundle_dir.join("Info.plist"))?;
     writeln!(plist, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
     writeln!(
@@ -25,9 +34,17 @@ pub fn add_plist_to_app(bundle: &BuildBundle, arch: &str, app_bundle_id: &str) -
     writeln!(plist, "<key>UIRequiredDeviceCapabilities</key>")?;
     writeln!(plist, "<array><string>{}</string></array>", arch)?;
     writeln!(plist, "<key>CFBundleVersion</key>")?;
-    writeln!(plist, "<string>{}</string>", arch)?;
+    writeln!(plist, "<string>{}</string>", version)?;
     writeln!(plist, "<key>CFBundleShortVersionString</key>")?;
-    writeln!(plist, "<string>{}</string>", arch)?;
+    writeln!(plist, "<string>{}</string>", version)?;
+    writeln!(plist, "<key>MinimumOSVersion</key>")?;
+    writeln!(plist, "<string>{}</string>", min_os_version)?;
+    writeln!(plist, "<key>UIDeviceFamily</key>")?;
+    writeln!(plist, "<array>")?;
+    for family in device_families {
+        writeln!(plist, "<integer>{}</integer>", family.plist_value())?;
+    }
+    writeln!(plist, "</array>")?;
     writeln!(plist, r#"</dict></plist>"#)?;
     /*
     let app_name = app_bundle_id.split(".").last().unwrap();
@@ -66,7 +83,13 @@ pub fn add_plist_to_app(bundle: &BuildBundle, arch: &str, app_bundle_id: &str) -
     Ok(())
 }
 
-pub fn sign_app(bundle: &BuildBundle, settings: &SignatureSettings) -> Result<()> {
+/// Signs the app with the identity of `settings`, looked up in the
+/// `ios_keychain` of `conf` if set, or else in the default keychains.
+pub fn sign_app(
+    bundle: &BuildBundle,
+    settings: &SignatureSettings,
+    conf: &Configuration,
+) -> Result<()> {
     debug!(
         "Will sign {:?} with team: {} using key: {} and profile: {}",
         bundle.bundle_dir, settings.identity.team, settings.identity.name, settings.file
@@ -84,20 +107,43 @@ pub fn sign_app(bundle: &BuildBundle, settings: &SignatureSettings) -> Result<()
     writeln!(plist, "{}", settings.entitlements)?;
     writeln!(plist, r#"</dict></plist>"#)?;
 
-    process::Command::new("codesign")
-        .args(&["-s", &*settings.identity.name, "--entitlements"])
+    let mut codesign = process::Command::new("codesign");
+    codesign.args(&["-s", &*settings.identity.name]);
+    if let Some(keychain) = &conf.ios_keychain {
+        codesign.arg("--keychain").arg(keychain);
+    }
+    if conf.ios_hardened_runtime == Some(true) {
+        codesign.args(&["-o", "runtime"]);
+    }
+    if conf.ios_timestamp == Some(true) {
+        codesign.arg("--timestamp");
+    }
+    codesign
+        .arg("--entitlements")
         .arg(entitlements)
         .arg(&bundle.bundle_dir)
         .status()?;
     Ok(())
 }
 
-pub fn look_for_signature_settings(device_id: &str) -> Result<Vec<SignatureSettings>> {
+/// Signature settings from the provisioning profiles of `device_id` matching
+/// the `ios_app_id` of `conf` (defaults to profiles for a "Dinghy" app) or any
+/// app, or from the `ios_provisioning_profile` of `conf` only if it is set.
+/// Identities are looked up in the `ios_keychain` of `conf` if set, or else in
+/// the default keychains.
+pub fn look_for_signature_settings(
+    device_id: &str,
+    conf: &Configuration,
+) -> Result<Vec<SignatureSettings>> {
+    let app_id = conf.ios_app_id.as_deref();
+    let keychain = conf.ios_keychain.as_deref();
+    let selected_profile = conf.ios_provisioning_profile.as_deref();
     let identity_regex = ::regex::Regex::new(r#"^ *[0-9]+\) ([A-Z0-9]{40}) "(.+)"$"#)?;
     let subject_regex = ::regex::Regex::new(r#"OU=([^,]+)"#)?;
     let mut identities: Vec<SigningIdentity> = vec![];
     let find_identities = process::Command::new("security")
         .args(&["find-identity", "-v", "-p", "codesigning"])
+        .args(keychain)
         .output()?;
     for line in String::from_utf8(find_identities.stdout)?.split("\n") {
         if let Some(caps) = identity_regex.captures(&line) {
@@ -108,9 +154,12 @@ pub fn look_for_signature_settings(device_id: &str) -> Result<Vec<SignatureSetti
             let subject = process::Command::new("sh")
                 .arg("-c")
                 .arg(format!(
-                    "security find-certificate -a -c \"{}\" -p | openssl x509 -text | \
+                    "security find-certificate -a -c \"{}\" -p {} | openssl x509 -text | \
                      grep Subject:",
-                    name
+                    name,
+                    keychain
+                        .map(|keychain| ::shell_escape::escape(keychain.into()).to_string())
+                        .unwrap_or_default()
                 ))
                 .output()?;
             let subject = String::from_utf8(subject.stdout)?;
@@ -153,30 +202,38 @@ pub fn look_for_signature_settings(device_id: &str) -> Result<Vec<SignatureSetti
         let dict = plist
             .as_dictionary()
             .ok_or_else(|| anyhow!("plist root should be a dictionary"))?;
-        let devices = if let Some(d) = dict.get("ProvisionedDevices") {
-            d
-        } else {
-            debug!("  no devices in profile");
-            continue;
-        };
-        let devices = if let Some(ds) = devices.as_array() {
-            ds
-        } else {
-            bail!("ProvisionedDevices expected to be array")
-        };
-        if !devices.contains(&plist::Value::String(device_id.into())) {
-            debug!("  no device match in profile");
-            continue;
-        }
         let name = dict
             .get("Name")
             .ok_or_else(|| anyhow!(format!("No name in profile {:?}", file.path())))?;
         let name = name
             .as_string()
             .ok_or_else(|| anyhow!("Name should have been a string in {:?}", file.path()))?;
-        if !name.ends_with("Dinghy") && !name.ends_with(" *") {
-            debug!("  app in profile does not match ({})", name);
-            continue;
+        if let Some(selected_profile) = selected_profile {
+            let uuid = dict.get("UUID").and_then(|uuid| uuid.as_string());
+            if uuid != Some(selected_profile) && name != selected_profile {
+                debug!("  not the selected profile");
+                continue;
+            }
+        } else {
+            let devices = if let Some(d) = dict.get("ProvisionedDevices") {
+                d
+            } else {
+                debug!("  no devices in profile");
+                continue;
+            };
+            let devices = if let Some(ds) = devices.as_array() {
+                ds
+            } else {
+                bail!("ProvisionedDevices expected to be array")
+            };
+            if !devices.contains(&plist::Value::String(device_id.into())) {
+                debug!("  no device match in profile");
+                continue;
+            }
+            if !name.ends_with(app_id.unwrap_or("Dinghy")) && !name.ends_with(" *") {
+                debug!("  app in profile does not match ({})", name);
+                continue;
+            }
         }
         // TODO: check date in future
         let team = dict
@@ -211,8 +268,13 @@ pub fn look_for_signature_settings(device_id: &str) -> Result<Vec<SignatureSetti
                 .to_str()
                 .ok_or_else(|| anyhow!("filename should be utf8"))?
                 .into(),
-            name: if name.ends_with(" *") {
-                "org.zoy.kali.Dinghy".into()
+            name: if selected_profile.is_some() {
+                profile_app_id(dict, &team)
+                    .filter(|it| !it.ends_with('*'))
+                    .unwrap_or(app_id.unwrap_or("org.zoy.kali.Dinghy"))
+                    .into()
+            } else if name.ends_with(" *") {
+                app_id.unwrap_or("org.zoy.kali.Dinghy").into()
             } else {
                 name.into()
             },
@@ -220,5 +282,27 @@ pub fn look_for_signature_settings(device_id: &str) -> Result<Vec<SignatureSetti
             profile: file.path().to_str().unwrap().into(),
         });
     }
-    Ok(settings)
+    match selected_profile {
+        Some(selected_profile) if settings.is_empty() => bail!(
+            "Provisioning profile {} not found, or no signing identity for its team",
+            selected_profile
+        ),
+        _ => Ok(settings),
+    }
+}
+
+/// App id of the `application-identifier` entitlement of a provisioning
+/// profile, without its `team` prefix.
+fn profile_app_id<'a>(profile: &'a plist::Dictionary, team: &str) -> Option<&'a str> {
+    let application_identifier = profile
+        .get("Entitlements")?
+        .as_dictionary()?
+        .get("application-identifier")?
+        .as_string()?;
+    Some(
+        application_identifier
+            .strip_prefix(team)
+            .and_then(|it| it.strip_prefix('.'))
+            .unwrap_or(application_identifier),
+    )
 }
@@ -46,8 +46,10 @@ mod ssh;
 mod toolchain;
 pub mod utils;
 
+pub use crate::android::AndroidDevice;
 pub use crate::compiler::Compiler;
 pub use crate::config::Configuration;
+pub use crate::ssh::SshDevice;
 
 use crate::compiler::CompileMode;
 use crate::config::PlatformConfiguration;
@@ -55,12 +57,30 @@ use crate::config::PlatformConfiguration;
 use crate::ios::IosManager;
 use crate::platform::regular_platform::RegularPlatform;
 use crate::project::Project;
+use crate::utils::run_with_timeout;
 use cargo::core::compiler::CompileKind;
 use std::fmt::Display;
-use std::{path, sync};
+use std::{path, sync, time};
 
 use crate::errors::Result;
 
+/// The rustc triple dinghy was built for.
+pub const HOST_TRIPLE: &str = env!("TARGET");
+
+/// Versions worth reporting with a bug: dinghy embeds cargo as a library and
+/// depends on its behaviour.
+pub fn version_details() -> String {
+    let cargo_version = cargo::version();
+    // cargo 1.x.z is the 0.(x+1).z library
+    format!(
+        "{} (embedded cargo crate 0.{}.{})\nhost: {}",
+        cargo_version,
+        cargo_version.minor + 1,
+        cargo_version.patch,
+        HOST_TRIPLE
+    )
+}
+
 pub struct Dinghy {
     devices: Vec<sync::Arc<Box<dyn Device>>>,
     platforms: Vec<(String, sync::Arc<Box<dyn Platform>>)>,
@@ -71,31 +91,40 @@ impl Dinghy {
         conf: &sync::Arc<Configuration>,
         compiler: &sync::Arc<Compiler>,
     ) -> Result<Dinghy> {
-        let mut managers: Vec<Box<dyn PlatformManager>> = vec![];
+        let mut managers: Vec<(&str, Box<dyn PlatformManager>)> = vec![];
         if let Some(man) = host::HostManager::probe(sync::Arc::clone(compiler), conf) {
-            managers.push(Box::new(man));
+            managers.push(("host", Box::new(man)));
         }
-        if let Some(man) = android::AndroidManager::probe(sync::Arc::clone(compiler)) {
-            managers.push(Box::new(man));
+        if let Some(man) = android::AndroidManager::probe(sync::Arc::clone(compiler), conf) {
+            managers.push(("Android", Box::new(man)));
         }
         if let Some(man) = script::ScriptDeviceManager::probe(conf.clone()) {
-            managers.push(Box::new(man));
+            managers.push(("script", Box::new(man)));
         }
         if let Some(man) = ssh::SshDeviceManager::probe(conf.clone()) {
-            managers.push(Box::new(man));
+            managers.push(("ssh", Box::new(man)));
         }
         #[cfg(target_os = "macos")]
         {
             std::thread::sleep(std::time::Duration::from_millis(100));
             if let Some(man) = IosManager::new(sync::Arc::clone(compiler))? {
-                managers.push(Box::new(man));
+                managers.push(("iOS", Box::new(man)));
             }
         }
 
         let mut devices = vec![];
         let mut platforms = vec![];
-        for man in managers.into_iter() {
-            devices.extend(man.devices()?.into_iter().map(|it| sync::Arc::new(it)));
+        let device_timeout = conf.device_timeout.map(time::Duration::from_secs);
+        for (kind, man) in managers.into_iter() {
+            let man = sync::Arc::new(man);
+            let discovering = sync::Arc::clone(&man);
+            match run_with_timeout(device_timeout, move || discovering.devices())? {
+                Some(found) => devices.extend(found.into_iter().map(|it| sync::Arc::new(it))),
+                None => warn!(
+                    "{} device discovery timed out, skipping {} devices",
+                    kind, kind
+                ),
+            }
             platforms.extend(
                 man.platforms()?
                     .into_iter()
@@ -132,6 +161,23 @@ impl Dinghy {
         self.devices.clone()
     }
 
+    /// Devices whose id or name contains `filter` (case insensitive), or all
+    /// devices if there is no filter.
+    pub fn find_devices(&self, filter: Option<&str>) -> Vec<sync::Arc<Box<dyn Device>>> {
+        let filter = filter.map(|it| it.to_lowercase());
+        self.devices
+            .iter()
+            .filter(|device| match &filter {
+                Some(filter) => {
+                    device.id().to_lowercase().contains(filter)
+                        || device.name().to_lowercase().contains(filter)
+                }
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
     pub fn host_device(&self) -> sync::Arc<Box<dyn Device>> {
         self.devices[0].clone()
     }
@@ -159,7 +205,13 @@ impl Dinghy {
     }
 }
 
-pub trait Device: std::fmt::Debug + Display + DeviceCompatibility {
+pub trait Device: std::fmt::Debug + Display + DeviceCompatibility + Send + Sync {
+    /// Checks the device is usable (reachable, writable work dir, remote
+    /// tools...). Devices that need no setup have nothing to check.
+    fn check(&self) -> Vec<DeviceCheck> {
+        vec![]
+    }
+
     fn clean_app(&self, build_bundle: &BuildBundle) -> Result<()>;
 
     fn debug_app(
@@ -172,17 +224,61 @@ pub trait Device: std::fmt::Debug + Display + DeviceCompatibility {
 
     fn id(&self) -> &str;
 
+    /// Installs the runnables of the build without running them. Returns the
+    /// remote bundle of each runnable with the command that runs it.
+    fn install_runnables(
+        &self,
+        _project: &Project,
+        _build: &Build,
+        _args: &[&str],
+        _envs: &[&str],
+    ) -> Result<Vec<(BuildBundle, String)>> {
+        bail!(
+            "Installing without running is not supported on {}",
+            self.id()
+        )
+    }
+
+    /// The kind of device, to tell devices apart without downcasting.
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Other
+    }
+
     fn name(&self) -> &str;
 
+    /// Rustc triple preferred among the compatible platforms, when the device
+    /// is selected without a platform.
+    fn preferred_target(&self) -> Option<&str> {
+        None
+    }
+
+    /// Copies the file `remote` of the device to `local` on the host.
+    fn pull(&self, _remote: &str, _local: &path::Path) -> Result<()> {
+        bail!("Copying files from {} is not supported", self.id())
+    }
+
+    /// Copies the host file `local` to `remote` on the device, creating the
+    /// missing parent directories.
+    fn push(&self, _local: &path::Path, _remote: &str) -> Result<()> {
+        bail!("Copying files to {} is not supported", self.id())
+    }
+
+    /// Installs and runs the runnables of the build. Returns their remote
+    /// bundles and the time spent installing them.
     fn run_app(
         &self,
         project: &Project,
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>>;
+    ) -> Result<(Vec<BuildBundle>, time::Duration)>;
 
-    fn start_remote_lldb(&self) -> Result<String>;
+    fn start_remote_lldb(&self, local_port: Option<u16>) -> Result<String>;
+
+    /// Stops what `start_remote_lldb` started on the device, if anything.
+    fn stop_remote_lldb(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub trait DeviceCompatibility {
@@ -198,21 +294,70 @@ pub trait DeviceCompatibility {
     fn is_compatible_with_ios_platform(&self, _platform: &ios::IosPlatform) -> bool {
         false
     }
+
+    /// `Some(true)` for an iOS simulator, `Some(false)` for a physical iOS
+    /// device, `None` for non-iOS devices.
+    #[cfg(target_os = "macos")]
+    fn is_ios_simulator(&self) -> Option<bool> {
+        None
+    }
 }
 
 pub trait Platform: std::fmt::Debug {
     fn build(&self, project: &Project, build_args: &BuildArgs) -> Result<Build>;
 
+    /// The platform settings of the configuration files, empty for platforms
+    /// that are not configured there.
+    fn configuration(&self) -> &PlatformConfiguration {
+        static EMPTY: PlatformConfiguration = PlatformConfiguration::empty();
+        &EMPTY
+    }
+
     fn id(&self) -> String;
 
     fn is_compatible_with(&self, device: &dyn Device) -> bool;
 
+    /// Why the platform can not run on the device, `None` if it can.
+    fn incompatibility_with(&self, device: &dyn Device) -> Option<String> {
+        if self.is_compatible_with(device) {
+            None
+        } else {
+            Some(format!(
+                "platform {} is not compatible with device {}",
+                self.id(),
+                device.id()
+            ))
+        }
+    }
+
     fn is_host(&self) -> bool;
     fn rustc_triple(&self) -> &str;
     fn as_cargo_kind(&self) -> CompileKind;
 
-    fn strip(&self, build: &Build) -> Result<()>;
+    /// Sets up the environment cargo builds with for the platform: compilers,
+    /// linker, pkg-config, overlays and platform `env`. Platforms building
+    /// with the environment as it is have nothing to set up.
+    fn setup_env(&self, _project: &Project, _build_args: &BuildArgs) -> Result<()> {
+        Ok(())
+    }
+
+    /// Strips the runnables, first saving their debug symbols to `.debug`
+    /// files with `debug_files`.
+    fn strip(&self, _build: &Build, _debug_files: bool) -> Result<()> {
+        bail!("Stripping is not supported for platform {}", self.id())
+    }
     fn sysroot(&self) -> Result<Option<path::PathBuf>>;
+
+    /// Root of the toolchain the platform builds with, if it is not the host one.
+    fn toolchain_root(&self) -> Option<path::PathBuf> {
+        None
+    }
+
+    /// Environment the runnables need at run time, set before the `--env`
+    /// variables. `setup_env` only covers the build.
+    fn runtime_env(&self) -> Vec<(String, String)> {
+        self.configuration().runtime_env()
+    }
 }
 
 impl Display for dyn Platform {
@@ -221,24 +366,108 @@ impl Display for dyn Platform {
     }
 }
 
-pub trait PlatformManager {
+pub trait PlatformManager: Send + Sync {
     fn devices(&self) -> Result<Vec<Box<dyn Device>>>;
     fn platforms(&self) -> Result<Vec<Box<dyn Platform>>>;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceKind {
+    Android,
+    Host,
+    /// iOS devices and simulators, see `DeviceCompatibility::is_ios_simulator`
+    Ios,
+    /// Devices dinghy does not provide itself
+    Other,
+    Script,
+    Ssh,
+}
+
+impl Display for DeviceKind {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(match self {
+            DeviceKind::Android => "android",
+            DeviceKind::Host => "host",
+            DeviceKind::Ios => "ios",
+            DeviceKind::Other => "other",
+            DeviceKind::Script => "script",
+            DeviceKind::Ssh => "ssh",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct DeviceCheck {
+    pub name: String,
+    /// A failed critical check makes the device unusable, other checks only
+    /// disable some features (compressed transfer, debugging...).
+    pub critical: bool,
+    pub result: Result<()>,
+}
+
+impl DeviceCheck {
+    pub fn new<S: Into<String>>(name: S, critical: bool, result: Result<()>) -> DeviceCheck {
+        DeviceCheck {
+            name: name.into(),
+            critical,
+            result,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Build {
     pub build_args: BuildArgs,
     pub dynamic_libraries: Vec<path::PathBuf>,
     pub runnables: Vec<Runnable>,
+    /// Program starting the runnables on the device, from the platform
+    /// `exec_prefix`
+    pub exec_prefix: Option<String>,
+    /// Program running the runnables on the host (e.g. qemu-user), from the
+    /// platform `runner`
+    pub runner: Option<String>,
+    pub target: CompileKind,
     pub target_path: path::PathBuf,
 }
 
+impl Build {
+    /// Keeps shard `index` of `count` disjoint shards of the runnables. They
+    /// are dealt by name, so every machine building the same runnables gets
+    /// the same shards.
+    pub fn shard(&mut self, index: usize, count: usize) -> Result<()> {
+        if index >= count {
+            bail!(
+                "Invalid shard {} of {}, shards are numbered from 0",
+                index,
+                count
+            )
+        }
+        let mut runnables = std::mem::take(&mut self.runnables);
+        runnables.sort_by(|a, b| a.id.cmp(&b.id));
+        self.runnables = runnables
+            .into_iter()
+            .enumerate()
+            .filter(|(ix, _)| ix % count == index)
+            .map(|(_, runnable)| runnable)
+            .collect();
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct BuildArgs {
     pub compile_mode: CompileMode,
     pub verbose: bool,
     pub forced_overlays: Vec<String>,
+    pub bundle_manifest: bool,
+    /// File the output of the runnables is copied to, see `--log-file`
+    pub log_file: Option<path::PathBuf>,
+    /// Tool (`strace` or `ltrace`) the runnables are run under on the device,
+    /// see `--trace`
+    pub trace: Option<String>,
+    /// Compare the `verified_test_data` files on the device with the host
+    /// ones after installing, see `--verify-test-data`
+    pub verify_test_data: bool,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -248,9 +477,22 @@ pub struct BuildBundle {
     pub bundle_exe: path::PathBuf,
     pub lib_dir: path::PathBuf,
     pub root_dir: path::PathBuf,
+    /// `test_data` shared by the bundles of a crate with `shared_test_data`,
+    /// the bundle `test_data` links to it.
+    pub test_data_dir: Option<path::PathBuf>,
 }
 
 impl BuildBundle {
+    /// The bundle moved under `base`, a device work directory, keeping the
+    /// bundle namespace of its root.
+    fn relocate_to<P: AsRef<path::Path>>(&self, base: P) -> Result<Self> {
+        let namespace = self
+            .root_dir
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid bundle root {}", self.root_dir.display()))?;
+        self.replace_prefix_with(base.as_ref().join(namespace))
+    }
+
     fn replace_prefix_with<P: AsRef<path::Path>>(&self, path: P) -> Result<Self> {
         Ok(BuildBundle {
             id: self.id.clone(),
@@ -273,6 +515,14 @@ impl BuildBundle {
                     .join(self.lib_dir.strip_prefix(&self.root_dir)?),
             ),
             root_dir: path.as_ref().to_path_buf(),
+            test_data_dir: match &self.test_data_dir {
+                Some(test_data_dir) => Some(utils::normalize_path(
+                    &path
+                        .as_ref()
+                        .join(test_data_dir.strip_prefix(&self.root_dir)?),
+                )),
+                None => None,
+            },
         })
     }
 }
@@ -283,3 +533,69 @@ pub struct Runnable {
     pub exe: path::PathBuf,
     pub source: path::PathBuf,
 }
+
+/// A host build of `runnables` with the default arguments, for the tests.
+#[cfg(test)]
+pub(crate) fn test_build(runnables: Vec<Runnable>) -> Build {
+    Build {
+        build_args: BuildArgs {
+            compile_mode: CompileMode::Test,
+            verbose: false,
+            forced_overlays: vec![],
+            bundle_manifest: false,
+            log_file: None,
+            trace: None,
+            verify_test_data: false,
+        },
+        dynamic_libraries: vec![],
+        runnables,
+        exec_prefix: None,
+        runner: None,
+        target: CompileKind::Host,
+        target_path: path::PathBuf::from("target"),
+    }
+}
+
+/// Runs `f` from `dir`, one test at a time as the working directory and the
+/// environment are shared by all the tests of the process.
+#[cfg(test)]
+pub(crate) fn in_dir<T>(dir: &path::Path, f: impl FnOnce() -> T) -> T {
+    static LOCK: sync::Mutex<()> = sync::Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let previous = std::env::current_dir().unwrap();
+    std::env::set_current_dir(dir).unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    std::env::set_current_dir(previous).unwrap();
+    result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_are_disjoint_and_cover_the_runnables() {
+        let build = test_build(
+            ["e", "b", "d", "a", "c"]
+                .iter()
+                .map(|id| Runnable {
+                    id: id.to_string(),
+                    ..Runnable::default()
+                })
+                .collect(),
+        );
+        let shards = (0..2)
+            .map(|index| {
+                let mut build = build.clone();
+                build.shard(index, 2).unwrap();
+                build
+                    .runnables
+                    .iter()
+                    .map(|it| it.id.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(shards, vec![vec!["a", "c", "e"], vec!["b", "d"]]);
+        assert!(build.clone().shard(2, 2).is_err());
+    }
+}
@@ -31,6 +31,8 @@ pub struct Overlay {
     pub id: String,
     pub path: PathBuf,
     pub scope: OverlayScope,
+    /// Link the libraries of the overlay statically.
+    pub static_libs: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -83,49 +85,78 @@ impl Overlayer {
             }
         }
 
+        let overlay_depth = configuration.overlay_depth.unwrap_or(1);
         overlayer.apply_overlay(
             Overlayer::from_conf(configuration)?
                 .into_iter()
                 .chain(path_to_try.into_iter().flat_map(|path_to_try| {
-                    Overlayer::from_directory(path_to_try).unwrap_or_default()
+                    Overlayer::from_directory(path_to_try, overlay_depth).unwrap_or_default()
                 }))
                 .unique_by(|overlay| overlay.id.clone())
                 .collect_vec(),
         )
     }
 
+    /// Configured overlays whose path is not a directory are skipped with a
+    /// warning, or fail with `strict_overlays`.
     fn from_conf(configuration: &PlatformConfiguration) -> Result<Vec<Overlay>> {
-        Ok(configuration
+        let mut overlays = vec![];
+        for (overlay_id, overlay_conf) in configuration
             .overlays
             .as_ref()
             .unwrap_or(&::std::collections::HashMap::new())
-            .into_iter()
-            .map(|(overlay_id, overlay_conf)| Overlay {
+        {
+            let path = PathBuf::from(overlay_conf.path.as_str());
+            if !path.is_dir() {
+                if configuration.strict_overlays.unwrap_or(false) {
+                    bail!(
+                        "Overlay '{}' path '{}' is not a directory",
+                        overlay_id,
+                        path.display()
+                    )
+                }
+                warn!(
+                    "Overlay '{}' path '{}' is not a directory, ignoring it",
+                    overlay_id,
+                    path.display()
+                );
+                continue;
+            }
+            overlays.push(Overlay {
                 id: overlay_id.to_string(),
-                path: PathBuf::from(overlay_conf.path.as_str()),
+                path,
                 scope: OverlayScope::Application,
-            })
-            .collect())
+                static_libs: overlay_conf.static_libs.unwrap_or(false),
+            });
+        }
+        Ok(overlays)
     }
 
-    fn from_directory<P: AsRef<Path>>(overlay_root_dir: P) -> Result<Vec<Overlay>> {
-        Ok(overlay_root_dir
-            .as_ref()
-            .read_dir()
-            .with_context(|| {
-                format!(
-                    "Couldn't read overlay root directory '{}'.",
-                    overlay_root_dir.as_ref().display()
-                )
-            })?
+    /// Overlays are the directories `depth` levels below the root, named
+    /// after their own directory.
+    fn from_directory<P: AsRef<Path>>(overlay_root_dir: P, depth: usize) -> Result<Vec<Overlay>> {
+        if !overlay_root_dir.as_ref().is_dir() {
+            bail!(
+                "Couldn't read overlay root directory '{}'.",
+                overlay_root_dir.as_ref().display()
+            )
+        }
+        let depth = depth.max(1);
+        Ok(WalkDir::new(overlay_root_dir.as_ref())
+            .follow_links(true)
+            .min_depth(depth)
+            .max_depth(depth)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
             .filter_map(|it| it.ok()) // Ignore invalid directories
-            .map(|it| it.path())
-            .filter(|it| it.is_dir())
+            .filter(|it| it.file_type().is_dir())
+            .map(|it| it.into_path())
             .filter_map(destructure_path)
             .map(|(overlay_dir_path, overlay_dir_name)| Overlay {
                 id: overlay_dir_name,
                 path: overlay_dir_path.to_path_buf(),
                 scope: OverlayScope::Application,
+                static_libs: false,
             })
             .collect())
     }
@@ -197,6 +228,11 @@ impl Overlayer {
                 );
             }
 
+            if overlay.static_libs {
+                // Makes the pkg-config crate ask for --static and link the .a
+                set_env_ifndef(envify(format!("{}_STATIC", overlay.id)), "1");
+            }
+
             // Override the 'prefix' pkg-config variable for the specified overlay only.
             set_env_ifndef(
                 envify(format!("PKG_CONFIG_{}_PREFIX", overlay.id)),
@@ -233,13 +269,15 @@ impl Overlayer {
             Ok(())
         }
 
-        let pc_file = self.work_dir.join(format!("{}.pc", self.platform_id));
+        let pc_file = self.work_dir.join(format!("{}.pc", overlay.id));
         let lib_list = WalkDir::new(&overlay.path)
             .max_depth(1)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
             .into_iter()
             .filter_map(|entry| entry.ok()) // Ignore unreadable files, maybe could warn...
-            .filter(|entry| file_has_ext(entry.path(), ".so"))
+            .filter(|entry| file_has_ext(entry.path(), ".so") || file_has_ext(entry.path(), ".a"))
             .filter_map(|e| lib_name_from(e.path()).ok())
+            .unique()
             .collect_vec();
 
         write_pkg_config_file(pc_file.as_path(), overlay.id.as_str(), &lib_list).with_context(
@@ -252,3 +290,50 @@ impl Overlayer {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_overlays_are_found_at_the_configured_depth() {
+        let root = tempfile::tempdir().unwrap();
+        create_dir_all(root.path().join("media/ffmpeg/lib")).unwrap();
+        create_dir_all(root.path().join("ml/tensorflow")).unwrap();
+
+        let ids = |depth| {
+            Overlayer::from_directory(root.path(), depth)
+                .unwrap()
+                .into_iter()
+                .map(|overlay| overlay.id)
+                .collect_vec()
+        };
+        assert_eq!(ids(1), vec!["media", "ml"]);
+        assert_eq!(ids(2), vec!["ffmpeg", "tensorflow"]);
+    }
+
+    #[test]
+    fn generated_pkg_config_lists_static_and_dynamic_libs() {
+        let overlay_dir = tempfile::tempdir().unwrap();
+        for lib in &["libbar.a", "libbar.so", "libfoo.a", "bar.h"] {
+            File::create(overlay_dir.path().join(lib)).unwrap();
+        }
+        let work_dir = tempfile::tempdir().unwrap();
+        let overlayer = Overlayer {
+            platform_id: "some-platform".to_string(),
+            rustc_triple: None,
+            sysroot: PathBuf::from("/"),
+            work_dir: work_dir.path().to_path_buf(),
+        };
+        overlayer
+            .generate_pkg_config_file(&Overlay {
+                id: "vendor".to_string(),
+                path: overlay_dir.path().to_path_buf(),
+                scope: OverlayScope::Application,
+                static_libs: true,
+            })
+            .unwrap();
+        let pc = std::fs::read_to_string(work_dir.path().join("vendor.pc")).unwrap();
+        assert!(pc.contains("\nLibs: -L${prefix}  -lbar -lfoo\n"), "{}", pc);
+    }
+}
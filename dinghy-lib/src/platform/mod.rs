@@ -8,7 +8,14 @@ use anyhow::Context;
 
 pub mod regular_platform;
 
-pub fn strip_runnable(runnable: &Runnable, mut command: Command) -> Result<()> {
+/// Strips a copy of the runnable executable. With `objcopy`, its debug
+/// symbols are first written to a `.debug` file next to it, which the stripped
+/// copy links to.
+pub fn strip_runnable(
+    runnable: &Runnable,
+    mut command: Command,
+    objcopy: Option<&str>,
+) -> Result<()> {
     let exe_stripped_name = file_name_as_str(&runnable.exe)?;
 
     let mut stripped_runnable = runnable.clone();
@@ -21,6 +28,18 @@ pub fn strip_runnable(runnable: &Runnable, mut command: Command) -> Result<()> {
     // Backup old runnable
     fs::copy(&runnable.exe, &stripped_runnable.exe)?;
 
+    let debug_file = runnable
+        .exe
+        .with_file_name(format!("{}.debug", exe_stripped_name));
+    if let Some(objcopy) = objcopy {
+        let mut command = Command::new(objcopy);
+        command
+            .arg("--only-keep-debug")
+            .arg(&runnable.exe)
+            .arg(&debug_file);
+        run_objcopy(command)?;
+    }
+
     let command = command.arg(&stripped_runnable.exe);
     debug!("Running command {:?}", command);
 
@@ -33,6 +52,15 @@ pub fn strip_runnable(runnable: &Runnable, mut command: Command) -> Result<()> {
         )
     }
 
+    if let Some(objcopy) = objcopy {
+        let mut command = Command::new(objcopy);
+        command
+            .arg(format!("--add-gnu-debuglink={}", debug_file.display()))
+            .arg(&stripped_runnable.exe);
+        run_objcopy(command)?;
+        info!("Debug symbols saved to {}", debug_file.display());
+    }
+
     debug!(
         "{} unstripped size = {} and stripped size = {}",
         runnable.exe.display(),
@@ -41,3 +69,18 @@ pub fn strip_runnable(runnable: &Runnable, mut command: Command) -> Result<()> {
     );
     Ok(())
 }
+
+fn run_objcopy(mut command: Command) -> Result<()> {
+    debug!("Running command {:?}", command);
+    let output = command
+        .output()
+        .with_context(|| format!("Couldn't run {:?}", command))?;
+    if !output.status.success() {
+        bail!(
+            "Error while exporting debug symbols ({:?})\nError: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr)
+        )
+    }
+    Ok(())
+}
@@ -40,6 +40,20 @@ impl RegularPlatform {
         rustc_triple: String,
         toolchain_path: P,
     ) -> Result<Box<dyn Platform>> {
+        let configured_sysroot = match &configuration.sysroot {
+            Some(sysroot) => {
+                let sysroot = PathBuf::from(sysroot);
+                if !sysroot.is_dir() {
+                    bail!(
+                        "Sysroot {} configured for platform {} is not a directory",
+                        sysroot.display(),
+                        id
+                    )
+                }
+                Some(sysroot)
+            }
+            None => None,
+        };
         if let Some(prefix) = configuration.deb_multiarch.clone() {
             return Ok(Box::new(RegularPlatform {
                 compiler: compiler.clone(),
@@ -49,7 +63,7 @@ impl RegularPlatform {
                     bin_dir: "/usr/bin".into(),
                     rustc_triple,
                     root: "/".into(),
-                    sysroot: Some("/".into()),
+                    sysroot: configured_sysroot.or_else(|| Some("/".into())),
                     cc: "gcc".to_string(),
                     binutils_prefix: prefix.clone(),
                     cc_prefix: prefix.clone(),
@@ -59,8 +73,7 @@ impl RegularPlatform {
         let toolchain_path = toolchain_path.as_ref();
         let toolchain_bin_path = toolchain_path.join("bin");
 
-        let mut bin: Option<PathBuf> = None;
-        let mut prefix: Option<String> = None;
+        let mut prefixes = vec![];
         for file in toolchain_bin_path.read_dir().with_context(|| {
             format!(
                 "Couldn't find toolchain directory {}",
@@ -71,21 +84,26 @@ impl RegularPlatform {
             if file.file_name().to_string_lossy().ends_with("-gcc")
                 || file.file_name().to_string_lossy().ends_with("-gcc.exe")
             {
-                bin = Some(toolchain_bin_path);
-                prefix = Some(
+                prefixes.push(
                     file.file_name()
                         .to_string_lossy()
                         .replace(".exe", "")
                         .replace("-gcc", ""),
                 );
-                break;
             }
         }
-        let bin_dir = bin.ok_or_else(|| anyhow!("no bin/*-gcc found in toolchain"))?;
-        let tc_triple = prefix
-            .ok_or_else(|| anyhow!("no gcc in toolchain"))?
-            .to_string();
-        let sysroot = find_sysroot(&toolchain_path)?;
+        let tc_triple = best_gcc_prefix(prefixes, &rustc_triple)
+            .ok_or_else(|| anyhow!("no bin/*-gcc found in toolchain"))?;
+        debug!(
+            "Using {}-gcc from {}",
+            tc_triple,
+            toolchain_bin_path.display()
+        );
+        let bin_dir = toolchain_bin_path;
+        let sysroot = match configured_sysroot {
+            Some(sysroot) => Some(sysroot),
+            None => find_sysroot(&toolchain_path, &rustc_triple)?,
+        };
 
         let toolchain = ToolchainConfig {
             bin_dir,
@@ -114,6 +132,50 @@ impl RegularPlatform {
     }
 }
 
+/// Picks, among the `*-gcc` prefixes of a toolchain, the one matching the most
+/// parts of `rustc_triple`, the architecture first. Falls back to the first
+/// prefix in alphabetical order so the choice does not depend on the
+/// directory listing order.
+fn best_gcc_prefix(mut prefixes: Vec<String>, rustc_triple: &str) -> Option<String> {
+    prefixes.sort();
+    let score = |prefix: &str| -> usize {
+        let parts: Vec<&str> = prefix.split('-').collect();
+        rustc_triple
+            .split('-')
+            .enumerate()
+            .filter(|(_, part)| *part != "unknown")
+            .map(|(ix, part)| match ix {
+                0 if parts.iter().any(|it| part.starts_with(it)) => 2,
+                _ if parts.contains(&part) => 1,
+                _ => 0,
+            })
+            .sum()
+    };
+    let best = prefixes.iter().map(|it| score(it)).max()?;
+    prefixes.into_iter().find(|it| score(it) == best)
+}
+
+impl RegularPlatform {
+    fn setup_cxx(&self) -> Result<()> {
+        let cxx = self.toolchain.cc_executable("c++");
+        if self.configuration.cxx_stdlib.is_none() && self.configuration.cxx_stdlib_path.is_none() {
+            return self.toolchain.setup_tool("CXX", &cxx);
+        }
+
+        let mut cxx_cmd = cxx;
+        if let Some(cxx_stdlib) = &self.configuration.cxx_stdlib {
+            cxx_cmd.push_str(&format!(" -stdlib={}", cxx_stdlib));
+            // Lets the cc crate link the matching library (c++ or stdc++)
+            self.toolchain
+                .setup_tool("CXXSTDLIB", cxx_stdlib.trim_start_matches("lib"))?;
+        }
+        if let Some(cxx_stdlib_path) = &self.configuration.cxx_stdlib_path {
+            cxx_cmd.push_str(&format!(" -L{}", cxx_stdlib_path));
+        }
+        self.toolchain.setup_cxx(&self.id, &cxx_cmd)
+    }
+}
+
 impl Display for RegularPlatform {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::result::Result<(), ::std::fmt::Error> {
         write!(f, "{:?}", self.toolchain.root)
@@ -122,9 +184,24 @@ impl Display for RegularPlatform {
 
 impl Platform for RegularPlatform {
     fn build(&self, project: &Project, build_args: &BuildArgs) -> Result<Build> {
+        self.setup_env(project, build_args)?;
+
+        trace!("Internally invoke cargo");
+        let mut build = self.compiler.build(self, &build_args)?;
+        build.exec_prefix = self.configuration.exec_prefix.clone();
+        build.runner = self.configuration.runner.clone();
+        Ok(build)
+    }
+
+    fn configuration(&self) -> &PlatformConfiguration {
+        &self.configuration
+    }
+
+    fn setup_env(&self, project: &Project, build_args: &BuildArgs) -> Result<()> {
         // Cleanup environment
         set_all_env(&[("LIBRARY_PATH", ""), ("LD_LIBRARY_PATH", "")]);
-        // Set custom env variables specific to the platform
+        // Set custom env variables, the platform ones overriding the global ones
+        set_all_env(&project.conf.env.iter().collect::<Vec<_>>());
         set_all_env(&self.configuration.env());
 
         if let Some(sr) = &self.toolchain.sysroot {
@@ -143,8 +220,7 @@ impl Platform for RegularPlatform {
                 .setup_tool("AS", &self.toolchain.binutils_executable("as"))?;
         }
         if Path::new(&self.toolchain.binutils_executable("c++")).exists() {
-            self.toolchain
-                .setup_tool("CXX", &self.toolchain.cc_executable("c++"))?;
+            self.setup_cxx()?;
         }
         if Path::new(&self.toolchain.cc_executable("cpp")).exists() {
             self.toolchain
@@ -169,6 +245,13 @@ impl Platform for RegularPlatform {
             linker_cmd.push_str(&forced_overlay);
             // TODO Add -L
         }
+        if let Some(cxx_stdlib_path) = &self.configuration.cxx_stdlib_path {
+            linker_cmd.push_str(&format!(" -L{}", cxx_stdlib_path));
+        }
+        for linker_arg in self.configuration.linker_args.iter().flatten() {
+            linker_cmd.push(' ');
+            linker_cmd.push_str(linker_arg);
+        }
         self.toolchain.setup_linker(&self.id, &linker_cmd)?;
 
         trace!("Setup pkg-config");
@@ -177,9 +260,7 @@ impl Platform for RegularPlatform {
         self.toolchain.setup_sysroot();
         trace!("Setup shims...");
         self.toolchain.shim_executables(&self.id)?;
-
-        trace!("Internally invoke cargo");
-        self.compiler.build(self, &build_args)
+        Ok(())
     }
 
     fn id(&self) -> String {
@@ -202,22 +283,31 @@ impl Platform for RegularPlatform {
         Ok(self.toolchain.sysroot.clone())
     }
 
+    fn toolchain_root(&self) -> Option<PathBuf> {
+        Some(self.toolchain.root.clone())
+    }
+
     fn as_cargo_kind(&self) -> CompileKind {
         CompileKind::Target(CompileTarget::new(self.rustc_triple()).unwrap())
     }
 
-    fn strip(&self, build: &Build) -> Result<()> {
+    fn strip(&self, build: &Build, debug_files: bool) -> Result<()> {
+        let objcopy = self.toolchain.binutils_executable("objcopy");
         for runnable in &build.runnables {
             platform::strip_runnable(
                 runnable,
                 Command::new(self.toolchain.binutils_executable("strip")),
+                Some(&*objcopy).filter(|_| debug_files),
             )?;
         }
         Ok(())
     }
 }
 
-fn find_sysroot<P: AsRef<Path>>(toolchain_path: P) -> Result<Option<PathBuf>> {
+/// For musl targets, the musl subdirectories of toolchains bundling several
+/// libcs are preferred, and a musl subdirectory without `sysroot` is the
+/// sysroot itself, like in musl-cross-make toolchains.
+fn find_sysroot<P: AsRef<Path>>(toolchain_path: P, rustc_triple: &str) -> Result<Option<PathBuf>> {
     let toolchain = toolchain_path.as_ref();
     let immediate = toolchain.join("sysroot");
     if immediate.is_dir() {
@@ -226,15 +316,99 @@ fn find_sysroot<P: AsRef<Path>>(toolchain_path: P) -> Result<Option<PathBuf>> {
             .ok_or_else(|| anyhow!("sysroot is not utf-8"))?;
         return Ok(Some(sysroot.into()));
     }
-    for subdir in toolchain.read_dir()? {
-        let subdir = subdir?;
-        let maybe = subdir.path().join("sysroot");
-        if maybe.is_dir() {
-            let sysroot = maybe
-                .to_str()
-                .ok_or_else(|| anyhow!("sysroot is not utf-8"))?;
-            return Ok(Some(sysroot.into()));
-        }
+    let is_musl = rustc_triple.ends_with("-musl");
+    let mut subdirs = toolchain
+        .read_dir()?
+        .map(|subdir| Ok(subdir?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    subdirs.sort_by_key(|subdir| (!(is_musl && is_musl_dir(subdir)), subdir.clone()));
+    for subdir in subdirs {
+        let maybe = subdir.join("sysroot");
+        let maybe = if maybe.is_dir() {
+            maybe
+        } else if is_musl
+            && is_musl_dir(&subdir)
+            && subdir.join("include").is_dir()
+            && subdir.join("lib").is_dir()
+        {
+            subdir
+        } else {
+            continue;
+        };
+        let sysroot = maybe
+            .to_str()
+            .ok_or_else(|| anyhow!("sysroot is not utf-8"))?;
+        return Ok(Some(sysroot.into()));
     }
     Ok(None)
 }
+
+fn is_musl_dir(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains("musl"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn gcc_prefix_matches_rustc_triple() {
+        let prefixes = vec![
+            "arm-none-eabi".to_string(),
+            "arm-linux-gnueabihf".to_string(),
+        ];
+        assert_eq!(
+            best_gcc_prefix(prefixes.clone(), "armv7-unknown-linux-gnueabihf").as_deref(),
+            Some("arm-linux-gnueabihf")
+        );
+        assert_eq!(
+            best_gcc_prefix(prefixes, "x86_64-unknown-linux-gnu").as_deref(),
+            Some("arm-linux-gnueabihf")
+        );
+        assert_eq!(best_gcc_prefix(vec![], "x86_64-unknown-linux-gnu"), None);
+    }
+
+    #[test]
+    fn musl_sysroots_are_preferred_for_musl_targets() {
+        let toolchain = tempfile::tempdir().unwrap();
+        let gnu = toolchain.path().join("aarch64-linux-gnu/sysroot");
+        let musl = toolchain.path().join("aarch64-linux-musl/sysroot");
+        fs::create_dir_all(&gnu).unwrap();
+        fs::create_dir_all(&musl).unwrap();
+        assert_eq!(
+            find_sysroot(toolchain.path(), "aarch64-unknown-linux-musl").unwrap(),
+            Some(musl)
+        );
+        assert_eq!(
+            find_sysroot(toolchain.path(), "aarch64-unknown-linux-gnu").unwrap(),
+            Some(gnu)
+        );
+
+        // musl-cross-make layout, the triple directory is the sysroot
+        let toolchain = tempfile::tempdir().unwrap();
+        let musl = toolchain.path().join("aarch64-linux-musl");
+        fs::create_dir_all(musl.join("include")).unwrap();
+        fs::create_dir_all(musl.join("lib/pkgconfig")).unwrap();
+        fs::create_dir_all(toolchain.path().join("share/pkgconfig")).unwrap();
+        assert_eq!(
+            find_sysroot(toolchain.path(), "aarch64-unknown-linux-musl").unwrap(),
+            Some(musl.clone())
+        );
+        let toolchain_config = ToolchainConfig {
+            bin_dir: toolchain.path().join("bin"),
+            root: toolchain.path().to_path_buf(),
+            rustc_triple: "aarch64-unknown-linux-musl".to_string(),
+            sysroot: Some(musl.clone()),
+            cc: "gcc".to_string(),
+            binutils_prefix: "aarch64-linux-musl".to_string(),
+            cc_prefix: "aarch64-linux-musl".to_string(),
+        };
+        assert_eq!(
+            toolchain_config.pkg_config_dirs(),
+            vec![musl.join("lib/pkgconfig").to_string_lossy().to_string()]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::config::dinghy_config;
 use crate::config::Configuration;
 use crate::utils::copy_and_sync_file;
+use crate::utils::target_dir;
 use crate::Platform;
 use crate::Result;
 use crate::Runnable;
@@ -37,13 +38,74 @@ impl Project {
     }
 
     pub fn target_dir(&self, platform: &CompileKind) -> Result<PathBuf> {
-        let mut target_path = self.project_dir()?.join("target");
+        let mut target_path = target_dir()?;
         if let CompileKind::Target(s) = platform {
             target_path = target_path.join(s.rustc_target());
         }
         Ok(target_path)
     }
 
+    /// Whether a dynamic library is listed in `device_provided_libs`, and
+    /// should be taken from the device rather than bundled. `libssl.so` also
+    /// matches versioned names like `libssl.so.1.1`.
+    pub fn is_device_provided_lib<P: AsRef<Path>>(&self, lib: P) -> bool {
+        let file_name = match lib.as_ref().file_name().and_then(|it| it.to_str()) {
+            Some(file_name) => file_name,
+            None => return false,
+        };
+        self.conf
+            .device_provided_libs
+            .iter()
+            .flatten()
+            .any(|provided| {
+                file_name == provided || file_name.starts_with(&format!("{}.", provided))
+            })
+    }
+
+    /// Directory name the bundles are made in, on the host and on the
+    /// devices. Defaults to `dinghy`, a distinct one isolates concurrent jobs
+    /// sharing a device.
+    pub fn bundle_namespace(&self) -> Result<&str> {
+        let namespace = self.conf.bundle_namespace.as_deref().unwrap_or("dinghy");
+        if namespace.is_empty()
+            || namespace == "."
+            || namespace == ".."
+            || namespace.contains(['/', '\\'])
+        {
+            bail!(
+                "Invalid bundle namespace '{}', it must be a plain directory name",
+                namespace
+            )
+        }
+        Ok(namespace)
+    }
+
+    /// Whether runnables get `DINGHY=1` in their environment (the default).
+    pub fn sets_dinghy_env(&self) -> bool {
+        self.conf.set_dinghy_env.unwrap_or(true)
+    }
+
+    /// Whether the bundled executables find the bundled libraries through
+    /// their rpath rather than only `LD_LIBRARY_PATH`.
+    pub fn sets_rpath(&self) -> bool {
+        self.conf.set_rpath.unwrap_or(false)
+    }
+
+    /// Whether the bundles of a crate share a single copy of its `test_data`
+    /// on the device instead of carrying one each.
+    pub fn shares_test_data(&self) -> bool {
+        self.conf.shared_test_data.unwrap_or(false)
+    }
+
+    /// Directory, on the device, the runnables are started from. Defaults to
+    /// the bundle directory, `run_cwd` is relative to it.
+    pub fn run_dir<P: AsRef<Path>>(&self, bundle_dir: P) -> PathBuf {
+        match &self.conf.run_cwd {
+            Some(cwd) => bundle_dir.as_ref().join(cwd),
+            None => bundle_dir.as_ref().to_path_buf(),
+        }
+    }
+
     pub fn for_runnable(&self, runnable: &Runnable) -> Result<Self> {
         Ok(Project {
             conf: Arc::new(dinghy_config(&runnable.source)?),
@@ -64,11 +126,23 @@ impl Project {
         debug!("Generating {}", test_data_cfg_path.display());
 
         for td in sub_project.conf.test_data.iter() {
-            let target_path = td
+            let mut target_path = td
                 .base
                 .parent()
                 .unwrap_or(&PathBuf::from("/"))
                 .join(&td.source);
+            if is_glob(&td.source) {
+                // There is no single path to link to, link to a copy of the matches
+                let copy_path = test_data_path.join(&td.id);
+                let _ = fs::remove_dir_all(&copy_path);
+                if copy_glob(&target_path, &copy_path, td.copy_git_ignored)? == 0 {
+                    warn!(
+                        "configuration required test_data `{:?}` but no file matches it",
+                        td
+                    );
+                }
+                target_path = copy_path;
+            }
             let target_path = target_path
                 .to_str()
                 .ok_or_else(|| anyhow!("Invalid UTF-8 path {}", target_path.display()))?;
@@ -92,7 +166,14 @@ impl Project {
                 .parent()
                 .unwrap_or(&PathBuf::from("/"))
                 .join(&td.source);
-            if Path::new(&file).exists() {
+            if is_glob(&td.source) {
+                if copy_glob(&file, test_data_path.join(&td.id), td.copy_git_ignored)? == 0 {
+                    warn!(
+                        "configuration required test_data `{:?}` but no file matches it",
+                        td
+                    );
+                }
+            } else if Path::new(&file).exists() {
                 let metadata = file.metadata()?;
                 let dst = test_data_path.join(&td.id);
                 if metadata.is_dir() {
@@ -111,6 +192,42 @@ impl Project {
     }
 }
 
+/// Whether a test_data `source` is a glob pattern rather than a path.
+pub fn is_glob(source: &str) -> bool {
+    source.contains(['*', '?', '['])
+}
+
+/// Copies what matches the glob `pattern` to `dst`, keeping the paths relative
+/// to the directory the pattern starts from. Returns the number of matches.
+fn copy_glob<P: AsRef<Path>>(
+    pattern: &Path,
+    dst: P,
+    copy_ignored_test_data: bool,
+) -> Result<usize> {
+    let root = pattern
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect::<PathBuf>();
+    let pattern = pattern
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid UTF-8 path {}", pattern.display()))?;
+    let mut matches = 0;
+    for path in glob::glob(pattern)? {
+        let path = path?;
+        let dst = dst.as_ref().join(path.strip_prefix(&root)?);
+        if path.is_dir() {
+            rec_copy(&path, &dst, copy_ignored_test_data)?;
+        } else {
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&path, &dst)?;
+        }
+        matches += 1;
+    }
+    Ok(matches)
+}
+
 pub fn rec_copy<P1: AsRef<Path>, P2: AsRef<Path>>(
     src: P1,
     dst: P2,
@@ -201,3 +318,35 @@ pub fn rec_copy_excl<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path> + ::std::
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestData;
+
+    #[test]
+    fn glob_test_data_keeps_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixtures = dir.path().join("fixtures");
+        fs::create_dir_all(fixtures.join("nested")).unwrap();
+        for file in &["a.json", "b.txt", "nested/c.json"] {
+            fs::write(fixtures.join(file), file).unwrap();
+        }
+        let mut conf = Configuration::default();
+        conf.test_data.push(TestData {
+            id: "fixtures".to_string(),
+            base: dir.path().join(".dinghy.toml"),
+            source: "fixtures/**/*.json".to_string(),
+            target: "fixtures/**/*.json".to_string(),
+            copy_git_ignored: false,
+        });
+        let app = dir.path().join("app");
+
+        Project::new(&Arc::new(conf)).copy_test_data(&app).unwrap();
+
+        let copied = app.join("test_data/fixtures");
+        assert!(copied.join("a.json").is_file());
+        assert!(copied.join("nested/c.json").is_file());
+        assert!(!copied.join("b.txt").exists());
+    }
+}
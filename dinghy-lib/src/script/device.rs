@@ -1,6 +1,8 @@
 use crate::config::ScriptDeviceConfiguration;
+use crate::device::{output_prefixed, run_and_tee};
+use crate::errors::DinghyError;
 use crate::*;
-use std::{fmt, fs, process};
+use std::{fmt, fs, process, time};
 
 #[derive(Debug)]
 pub struct ScriptDevice {
@@ -46,6 +48,10 @@ impl Device for ScriptDevice {
         &self.id
     }
 
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Script
+    }
+
     fn name(&self) -> &str {
         &self.id
     }
@@ -56,17 +62,20 @@ impl Device for ScriptDevice {
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>> {
-        let root_dir = build.target_path.join("dinghy");
+    ) -> Result<(Vec<BuildBundle>, time::Duration)> {
+        let root_dir = build.target_path.join(project.bundle_namespace()?);
         let mut build_bundles = vec![];
+        let mut install_time = time::Duration::ZERO;
         for runnable in &build.runnables {
             let bundle_path = &runnable.source;
 
             trace!("About to start runner script...");
+            let install_start = time::Instant::now();
             let test_data_path = project.link_test_data(&runnable, &bundle_path)?;
+            install_time += install_start.elapsed();
 
-            let status = self
-                .command(build)?
+            let mut command = self.command(build)?;
+            command
                 .arg(&runnable.exe)
                 .current_dir(&runnable.source)
                 .env("DINGHY_TEST_DATA_PATH", test_data_path)
@@ -84,10 +93,16 @@ impl Device for ScriptDevice {
                             ))
                         })
                         .collect::<Result<Vec<_>>>()?,
-                )
-                .status()?;
+                );
+            let status = if output_prefixed() {
+                run_and_tee(&mut command, None)?.0
+            } else {
+                command.status()?
+            };
             if !status.success() {
-                bail!("Test failed")
+                bail!(DinghyError::RemoteCommandFailed {
+                    code: status.code()
+                })
             }
 
             build_bundles.push(BuildBundle {
@@ -96,12 +111,13 @@ impl Device for ScriptDevice {
                 bundle_exe: runnable.exe.to_path_buf(),
                 lib_dir: build.target_path.clone(),
                 root_dir: root_dir.clone(),
+                test_data_dir: None,
             });
         }
-        Ok(build_bundles)
+        Ok((build_bundles, install_time))
     }
 
-    fn start_remote_lldb(&self) -> Result<String> {
+    fn start_remote_lldb(&self, _local_port: Option<u16>) -> Result<String> {
         unimplemented!()
     }
 }
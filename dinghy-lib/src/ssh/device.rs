@@ -1,28 +1,126 @@
+use crate::config::Compression;
 use crate::config::SshDeviceConfiguration;
+use crate::config::Transfer;
+use crate::device::compress_bundle;
+use crate::device::criterion_dirs;
+use crate::device::fetch_trace;
+use crate::device::install_shared_test_data;
+use crate::device::lldb_gdbserver_ports;
+use crate::device::lldb_platform_command;
 use crate::device::make_remote_app;
+use crate::device::open_log_file;
+use crate::device::output_prefixed;
+use crate::device::remote_run_command;
+use crate::device::remote_uncompress_check_command;
+use crate::device::remote_uncompress_command;
+use crate::device::run_and_tee;
+use crate::device::verify_test_data;
+use crate::device::wait_for_lldb_platform_port;
 use crate::errors::*;
 use crate::host::HostPlatform;
 use crate::platform::regular_platform::RegularPlatform;
 use crate::project::Project;
+use crate::utils::check_status;
+use crate::utils::command_line;
+use crate::utils::free_local_port;
 use crate::utils::path_to_str;
 use crate::Build;
 use crate::BuildBundle;
 use crate::Device;
+use crate::DeviceCheck;
 use crate::DeviceCompatibility;
+use crate::DeviceKind;
 use crate::Runnable;
+use cargo::core::compiler::CompileMode;
+use std::collections::HashSet;
+use std::env;
 use std::fmt;
 use std::fmt::Formatter;
 use std::fmt::{Debug, Display};
+use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Exit code of ssh itself failing, e.g. when the connection is closed.
+const SSH_CONNECTION_ERROR: i32 = 255;
+/// Runs started again after the device closed the connection.
+const MAX_REBOOTS: usize = 3;
+/// Probes, a second apart, telling a device going down from a runnable that
+/// exited with 255 itself.
+const DOWN_PROBES: u32 = 3;
+/// Attempts to reach a rebooting device, with exponential backoff.
+const RECONNECT_ATTEMPTS: u32 = 8;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
 pub struct SshDevice {
     pub id: String,
     pub conf: SshDeviceConfiguration,
+    /// The lldb platform, the tunnel to it and the command line it runs.
+    lldb_server: Mutex<Option<(Child, Child, String)>>,
+    installed_test_data: Mutex<HashSet<PathBuf>>,
 }
 
 impl SshDevice {
+    /// A device from its configuration, nothing is run until it is used.
+    pub fn new(id: String, conf: SshDeviceConfiguration) -> SshDevice {
+        if conf.password.is_some() && conf.password_env.is_none() {
+            warn!(
+                "Storing the password of {} in the configuration is insecure, prefer password_env",
+                id
+            );
+        }
+        SshDevice {
+            id,
+            conf,
+            lldb_server: Mutex::new(None),
+            installed_test_data: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The ssh command running the installed `remote_bundle`. `args` are
+    /// expanded with the `remote_shell_vars` of the device.
+    pub fn run_command(
+        &self,
+        project: &Project,
+        build: &Build,
+        remote_bundle: &BuildBundle,
+        args: &[&str],
+        envs: &[&str],
+    ) -> Result<Command> {
+        let remote_shell_vars_as_context = |a: &str| -> Option<std::borrow::Cow<str>> {
+            self.conf.remote_shell_vars.get(a).map(|s| s.into())
+        };
+        let args: Vec<String> = args
+            .iter()
+            .map(|&a| {
+                shellexpand::full_with_context_no_errors(
+                    a,
+                    || remote_shell_vars_as_context("HOME").map(|s| PathBuf::from(&*s)),
+                    remote_shell_vars_as_context,
+                )
+            })
+            .map(|a| ::shell_escape::escape(a).to_string())
+            .collect();
+        let command = remote_run_command(
+            project,
+            build,
+            remote_bundle,
+            &self.conf.extra_library_paths,
+            self.conf.exec_prefix.as_deref(),
+            envs,
+            &args,
+        )?;
+        trace!("Ssh command: {}", command);
+        let mut ssh = self.ssh_command()?;
+        ssh.arg(&command);
+        Ok(ssh)
+    }
+
     fn install_app(
         &self,
         project: &Project,
@@ -43,19 +141,226 @@ impl SshDevice {
             .status();
 
         info!("Install {} to {}", runnable.id, self.id);
-        self.sync(&build_bundle.bundle_dir, &remote_bundle.bundle_dir)?;
-        self.sync(&build_bundle.lib_dir, &remote_bundle.lib_dir)?;
+        install_shared_test_data(
+            &build_bundle,
+            &remote_bundle,
+            &self.installed_test_data,
+            |local, remote| {
+                let _ = self
+                    .ssh_command()?
+                    .arg("mkdir")
+                    .arg("-p")
+                    .arg(remote)
+                    .status();
+                self.sync(local, remote)
+            },
+        )?;
+        if let Some(compression) = project.conf.transfer_compression {
+            if self.install_compressed(&build_bundle, &remote_bundle, compression)? {
+                return Ok((build_bundle, remote_bundle));
+            }
+        }
+        if self.conf.parallel_sync.unwrap_or(false) {
+            // The two transfers share the bandwidth limit
+            let bwlimit = self.conf.rsync_bwlimit.map(|it| (it / 2).max(1));
+            // Installed once, before the transfers start
+            let rsync = self.remote_rsync()?;
+            let rsync = rsync.as_deref();
+            thread::scope(|scope| {
+                let libs = scope.spawn(|| {
+                    self.copy(
+                        &build_bundle.lib_dir,
+                        &remote_bundle.lib_dir,
+                        bwlimit,
+                        rsync,
+                    )
+                });
+                let bundle = self.copy(
+                    &build_bundle.bundle_dir,
+                    &remote_bundle.bundle_dir,
+                    bwlimit,
+                    rsync,
+                );
+                libs.join()
+                    .map_err(|_| anyhow!("Library sync to {} panicked", self.id))?
+                    .and(bundle)
+            })?;
+        } else {
+            self.sync(&build_bundle.bundle_dir, &remote_bundle.bundle_dir)?;
+            self.sync(&build_bundle.lib_dir, &remote_bundle.lib_dir)?;
+        }
         Ok((build_bundle, remote_bundle))
     }
 
+    fn install_compressed(
+        &self,
+        build_bundle: &BuildBundle,
+        remote_bundle: &BuildBundle,
+        compression: Compression,
+    ) -> Result<bool> {
+        let mut check = self.ssh_command()?;
+        check.arg(remote_uncompress_check_command(compression));
+        debug!("Running {:?}", check);
+        if !check.status()?.success() {
+            warn!(
+                "tar or {} missing on {}, falling back to uncompressed transfer",
+                compression.program(),
+                self.id
+            );
+            return Ok(false);
+        }
+
+        let archive = compress_bundle(build_bundle, compression)?;
+        let remote_archive = remote_bundle.root_dir.join(
+            archive
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid archive path {}", archive.display()))?,
+        );
+        let mut command = self.tool_command("scp")?;
+        if let Some(port) = self.conf.port {
+            command.arg("-P").arg(format!("{}", port));
+        }
+        if !log_enabled!(::log::Level::Debug) {
+            command.arg("-q");
+        }
+        command.arg(&archive).arg(format!(
+            "{}@{}:{}",
+            self.conf.username,
+            self.conf.hostname,
+            path_to_str(&remote_archive)?
+        ));
+        debug!("Running {:?}", command);
+        check_status(&mut command).context("Error copying archive to ssh device")?;
+
+        let mut command = self.ssh_command()?;
+        command.arg(remote_uncompress_command(
+            remote_bundle,
+            &remote_archive,
+            compression,
+        )?);
+        debug!("Running {:?}", command);
+        check_status(&mut command).context("Error extracting archive on ssh device")?;
+        Ok(true)
+    }
+
+    /// Waits for the device to accept connections again, backing off
+    /// exponentially between attempts.
+    fn wait_for_reconnect(&self) -> Result<()> {
+        for attempt in 0..RECONNECT_ATTEMPTS {
+            let delay = reconnect_delay(attempt);
+            debug!("Reconnecting to {} in {:?}", self.id, delay);
+            thread::sleep(delay);
+            if self.reachable()? {
+                info!("Reconnected to {}", self.id);
+                // The device may have lost its temporary files
+                self.installed_test_data
+                    .lock()
+                    .map_err(|_| anyhow!("poisoned lock"))?
+                    .clear();
+                return Ok(());
+            }
+        }
+        bail!(
+            "{} did not come back after {} reconnection attempts",
+            self.id,
+            RECONNECT_ATTEMPTS
+        )
+    }
+
+    /// Whether the device went down after ssh exited with 255: a runnable
+    /// exiting with 255 looks the same, but then the device stays reachable.
+    fn went_down(&self) -> Result<bool> {
+        for probe in 0..DOWN_PROBES {
+            if probe > 0 {
+                thread::sleep(Duration::from_secs(1));
+            }
+            if !self.reachable()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Copies a small directory to the work dir with the configured transfer
+    /// tool, and removes it once it made it to the device.
+    fn check_transfer(&self) -> Result<()> {
+        let local = env::temp_dir().join(format!("dinghy-check-{}", std::process::id()));
+        fs::create_dir_all(&local)?;
+        fs::write(local.join("check"), "dinghy")?;
+        let remote = self.remote_root().join("dinghy-check");
+        let remote = path_to_str(&remote)?;
+        let result = self
+            .check_remote(&format!("mkdir -p '{}'", remote))
+            .and_then(|_| self.sync(&local, remote))
+            .and_then(|_| {
+                self.check_remote(&format!("test -f '{0}/check' && rm -rf '{0}'", remote))
+            });
+        let _ = fs::remove_dir_all(&local);
+        result
+    }
+
+    /// Whether a no-op ssh command succeeds on the device.
+    fn reachable(&self) -> Result<bool> {
+        let mut command = self.ssh_command_with_options(&["-o", "ConnectTimeout=5"])?;
+        command.arg("true");
+        Ok(command.output()?.status.success())
+    }
+
+    fn check_remote(&self, remote_command: &str) -> Result<()> {
+        let mut command = self.ssh_command()?;
+        command.arg(remote_command);
+        debug!("Running {:?}", command);
+        let status = command.output()?.status;
+        if !status.success() {
+            return Err(anyhow!(DinghyError::CommandFailed {
+                command: command_line(&command),
+                code: status.code(),
+            })
+            .context(format!("'{}' failed on {}", remote_command, self.id)));
+        }
+        Ok(())
+    }
+
+    /// Command running `program`, wrapped with sshpass when the device uses
+    /// password authentication.
+    fn tool_command(&self, program: &str) -> Result<Command> {
+        let password = match (&self.conf.password_env, &self.conf.password) {
+            (Some(var), _) => Some(
+                env::var(var)
+                    .with_context(|| format!("{} must hold the password of {}", var, self.id))?,
+            ),
+            (None, Some(password)) => Some(password.clone()),
+            (None, None) => None,
+        };
+        match password {
+            Some(password) => {
+                if which::which("sshpass").is_err() {
+                    bail!(
+                        "sshpass is required for password authentication on {}, but it was not found",
+                        self.id
+                    )
+                }
+                let mut command = Command::new("sshpass");
+                command.arg("-e").arg(program).env("SSHPASS", password);
+                Ok(command)
+            }
+            None => Ok(Command::new(program)),
+        }
+    }
+
     fn ssh_command(&self) -> Result<Command> {
-        let mut command = Command::new("ssh");
+        self.ssh_command_with_options(&[])
+    }
+
+    fn ssh_command_with_options(&self, options: &[&str]) -> Result<Command> {
+        let mut command = self.tool_command("ssh")?;
         if let Some(port) = self.conf.port {
             command.arg("-p").arg(&format!("{}", port));
         }
         if atty::is(atty::Stream::Stdout) {
             command.arg("-t").arg("-o").arg("LogLevel=QUIET");
         }
+        command.args(options);
         command.arg(format!("{}@{}", self.conf.username, self.conf.hostname));
         Ok(command)
     }
@@ -64,7 +369,7 @@ impl SshDevice {
         match rsync {
             Some(rsync) => {
                 let rsync_path = "/tmp/rsync";
-                let mut command = Command::new("scp");
+                let mut command = self.tool_command("scp")?;
                 command.arg("-q");
                 if let Some(port) = self.conf.port {
                     command.arg("-P").arg(&format!("{}", port));
@@ -75,24 +380,93 @@ impl SshDevice {
                     self.conf.username, self.conf.hostname, rsync_path
                 ));
                 debug!("Running {:?}", command);
-                if !command.status()?.success() {
-                    bail!("Error copying rsync binary ({:?})", command)
-                }
+                check_status(&mut command).context("Error copying rsync binary")?;
                 Ok(rsync_path.to_string())
             }
             None => Ok("/usr/bin/rsync".to_string()),
         }
     }
 
+    /// Transfer tool to use, falling back to scp when rsync is missing on the host.
+    fn transfer(&self) -> Result<Transfer> {
+        let rsync_found = which::which("rsync").is_ok();
+        let scp_found = which::which("scp").is_ok();
+        match self.conf.transfer.unwrap_or(Transfer::Rsync) {
+            Transfer::Rsync if rsync_found => Ok(Transfer::Rsync),
+            Transfer::Rsync if scp_found => {
+                warn!(
+                    "rsync not found on the host, using scp to copy to {}",
+                    self.id
+                );
+                Ok(Transfer::Scp)
+            }
+            Transfer::Rsync => bail!(
+                "Neither rsync nor scp found on the host, can not copy to {}",
+                self.id
+            ),
+            Transfer::Scp if scp_found => Ok(Transfer::Scp),
+            Transfer::Scp => bail!("scp not found on the host, can not copy to {}", self.id),
+        }
+    }
+
+    fn scp_command(&self) -> Result<Command> {
+        let mut command = self.tool_command("scp")?;
+        command.arg("-r");
+        if let Some(port) = self.conf.port {
+            command.arg("-P").arg(format!("{}", port));
+        }
+        if !log_enabled!(::log::Level::Debug) {
+            command.arg("-q");
+        }
+        Ok(command)
+    }
+
     fn sync<FP: AsRef<Path>, TP: AsRef<Path>>(&self, from_path: FP, to_path: TP) -> Result<()> {
-        let rsync = self.sync_rsync(self.conf.install_adhoc_rsync_local_path.clone());
-        let rsync = match rsync {
-            Ok(rsync_path) => rsync_path,
+        self.sync_with_limit(from_path, to_path, self.conf.rsync_bwlimit)
+    }
+
+    /// Copies `from_path` to the device, at most at `bwlimit` KiB/s.
+    fn sync_with_limit<FP: AsRef<Path>, TP: AsRef<Path>>(
+        &self,
+        from_path: FP,
+        to_path: TP,
+        bwlimit: Option<u32>,
+    ) -> Result<()> {
+        let rsync = self.remote_rsync()?;
+        self.copy(from_path, to_path, bwlimit, rsync.as_deref())
+    }
+
+    /// Path of rsync on the device, installing the adhoc one if configured.
+    /// `None` when copying with scp.
+    fn remote_rsync(&self) -> Result<Option<String>> {
+        if self.transfer()? == Transfer::Scp {
+            return Ok(None);
+        }
+        match self.sync_rsync(self.conf.install_adhoc_rsync_local_path.clone()) {
+            Ok(rsync_path) => Ok(Some(rsync_path)),
             Err(error) => bail!("Problem with rsync on the target: {:?}", error),
+        }
+    }
+
+    /// Copies `from_path` to the device with the remote `rsync`, or with scp
+    /// when there is none.
+    fn copy<FP: AsRef<Path>, TP: AsRef<Path>>(
+        &self,
+        from_path: FP,
+        to_path: TP,
+        bwlimit: Option<u32>,
+        rsync: Option<&str>,
+    ) -> Result<()> {
+        let rsync = match rsync {
+            Some(rsync) => rsync,
+            None => return self.sync_scp(from_path.as_ref(), to_path.as_ref(), bwlimit),
         };
-        let mut command = Command::new("rsync");
+        let mut command = self.tool_command("rsync")?;
         command.arg(&format!("--rsync-path={}", rsync));
         command.arg("-a").arg("-v");
+        if let Some(bwlimit) = bwlimit {
+            command.arg(format!("--bwlimit={}", bwlimit));
+        }
         if let Some(port) = self.conf.port {
             command.arg("-e").arg(&*format!("ssh -p {}", port));
         };
@@ -109,17 +483,121 @@ impl SshDevice {
                 path_to_str(&to_path.as_ref())?
             ));
         debug!("Running {:?}", command);
-        if !command.status().with_context(||format!("failed to run '{:?}'", command))?.success() {
-            bail!("Error syncing ssh directory ({:?})", command)
-        } else {
-            Ok(())
+        check_status(&mut command).context("Error syncing ssh directory")
+    }
+
+    fn sync_scp(&self, from_path: &Path, to_path: &Path, bwlimit: Option<u32>) -> Result<()> {
+        let mut command = self.scp_command()?;
+        if let Some(bwlimit) = bwlimit {
+            // scp limits in Kbit/s
+            command.arg("-l").arg(format!("{}", bwlimit * 8));
+        }
+        let mut has_entries = false;
+        for entry in fs::read_dir(from_path)? {
+            command.arg(entry?.path());
+            has_entries = true;
+        }
+        if !has_entries {
+            return Ok(());
         }
+        command.arg(format!(
+            "{}@{}:{}/",
+            self.conf.username,
+            self.conf.hostname,
+            path_to_str(to_path)?
+        ));
+        debug!("Running {:?}", command);
+        check_status(&mut command).context("Error copying to ssh device")
+    }
+
+    /// Work directory on the device, the bundle namespaces live in it.
+    fn remote_root(&self) -> PathBuf {
+        PathBuf::from(self.conf.path.clone().unwrap_or("/tmp".into()))
+    }
+
+    /// Copies back `from_path` from the device, if it exists there.
+    fn fetch<FP: AsRef<Path>, TP: AsRef<Path>>(&self, from_path: FP, to_path: TP) -> Result<()> {
+        let from_path = path_to_str(from_path.as_ref())?;
+        if self
+            .check_remote(&format!("test -d '{}'", from_path))
+            .is_err()
+        {
+            debug!("Nothing to fetch at {}", from_path);
+            return Ok(());
+        }
+        fs::create_dir_all(to_path.as_ref())?;
+        if self.transfer()? == Transfer::Scp {
+            // scp copies the directory itself: copy it into the parent, both
+            // ends share the same directory name.
+            let to_parent = to_path
+                .as_ref()
+                .parent()
+                .ok_or_else(|| anyhow!("Invalid fetch path {}", to_path.as_ref().display()))?;
+            let mut command = self.scp_command()?;
+            command
+                .arg(format!(
+                    "{}@{}:{}",
+                    self.conf.username, self.conf.hostname, from_path
+                ))
+                .arg(to_parent);
+            debug!("Running {:?}", command);
+            return check_status(&mut command).context("Error fetching from ssh device");
+        }
+        let mut command = self.tool_command("rsync")?;
+        command.arg("-a");
+        if let Some(port) = self.conf.port {
+            command.arg("-e").arg(format!("ssh -p {}", port));
+        };
+        command
+            .arg(format!(
+                "{}@{}:{}/",
+                self.conf.username, self.conf.hostname, from_path
+            ))
+            .arg(format!("{}/", path_to_str(to_path.as_ref())?));
+        debug!("Running {:?}", command);
+        check_status(&mut command).context("Error fetching from ssh device")
     }
 
     fn to_remote_bundle(&self, build_bundle: &BuildBundle) -> Result<BuildBundle> {
-        let remote_prefix =
-            PathBuf::from(self.conf.path.clone().unwrap_or("/tmp".into())).join("dinghy");
-        build_bundle.replace_prefix_with(remote_prefix)
+        build_bundle.relocate_to(self.remote_root())
+    }
+
+    /// Command copying the single file `from` to `to`, one of them a
+    /// `remote_path`, with the remote `rsync` or with scp when there is none.
+    fn copy_file_command(&self, from: &str, to: &str, rsync: Option<&str>) -> Result<Command> {
+        let mut command = if let Some(rsync) = rsync {
+            let mut command = self.tool_command("rsync")?;
+            command.arg(format!("--rsync-path={}", rsync));
+            command.arg("-a");
+            if let Some(bwlimit) = self.conf.rsync_bwlimit {
+                command.arg(format!("--bwlimit={}", bwlimit));
+            }
+            if let Some(port) = self.conf.port {
+                command.arg("-e").arg(format!("ssh -p {}", port));
+            };
+            command
+        } else {
+            let mut command = self.scp_command()?;
+            if let Some(bwlimit) = self.conf.rsync_bwlimit {
+                // scp limits in Kbit/s
+                command.arg("-l").arg(format!("{}", bwlimit * 8));
+            }
+            command
+        };
+        command.arg(from).arg(to);
+        debug!("Running {:?}", command);
+        Ok(command)
+    }
+
+    /// `path` on the device, as rsync and scp take it. The remote shell
+    /// expands it, so it is quoted.
+    fn remote_path(&self, path: &str) -> String {
+        format!(
+            "{}@{}:{}",
+            self.conf.username,
+            self.conf.hostname,
+            ::shell_escape::escape(path.into())
+        )
     }
 }
 
@@ -140,18 +618,43 @@ impl DeviceCompatibility for SshDevice {
 }
 
 impl Device for SshDevice {
-    fn clean_app(&self, build_bundle: &BuildBundle) -> Result<()> {
-        let status = self
-            .ssh_command()?
-            .arg(&format!(
-                "rm -rf {}",
-                path_to_str(&build_bundle.bundle_exe)?
-            ))
-            .status()?;
-        if !status.success() {
-            bail!("test fail.")
+    fn check(&self) -> Vec<DeviceCheck> {
+        let reachable = self.check_remote("true");
+        if reachable.is_err() {
+            return vec![DeviceCheck::new("device reachable", true, reachable)];
         }
-        Ok(())
+        let work_dir = path_to_str(&self.remote_root())
+            .and_then(|it| self.check_remote(&format!("mkdir -p '{}' && test -w '{}'", it, it)));
+        let transfer = self.transfer();
+        let transfer_name = match transfer {
+            Ok(Transfer::Rsync) => "file transfer (rsync)",
+            Ok(Transfer::Scp) => "file transfer (scp)",
+            Err(_) => "file transfer",
+        };
+        let transfer = transfer.and_then(|_| self.check_transfer());
+        vec![
+            DeviceCheck::new("device reachable", true, reachable),
+            DeviceCheck::new("work dir writable", true, work_dir),
+            DeviceCheck::new(transfer_name, true, transfer),
+            DeviceCheck::new(
+                "tar available (compressed transfer)",
+                false,
+                self.check_remote("command -v tar"),
+            ),
+            DeviceCheck::new(
+                "lldb-server available (lldbproxy)",
+                false,
+                self.check_remote("command -v lldb-server"),
+            ),
+        ]
+    }
+
+    fn clean_app(&self, build_bundle: &BuildBundle) -> Result<()> {
+        check_status(self.ssh_command()?.arg(&format!(
+            "rm -rf {}",
+            path_to_str(&build_bundle.bundle_exe)?
+        )))
+        .with_context(|| format!("Failure in ssh clean on {}", self.id))
     }
 
     fn debug_app(
@@ -168,64 +671,236 @@ impl Device for SshDevice {
         &self.id
     }
 
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Ssh
+    }
+
     fn name(&self) -> &str {
         &self.id
     }
 
+    fn pull(&self, remote: &str, local: &Path) -> Result<()> {
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let rsync = self.remote_rsync()?;
+        let mut command = self.copy_file_command(
+            &self.remote_path(remote),
+            path_to_str(local)?,
+            rsync.as_deref(),
+        )?;
+        check_status(&mut command)
+            .with_context(|| format!("Error pulling {} from {}", remote, self.id))
+    }
+
+    fn push(&self, local: &Path, remote: &str) -> Result<()> {
+        if let Some(parent) = Path::new(remote).parent() {
+            self.check_remote(&format!(
+                "mkdir -p {}",
+                ::shell_escape::escape(path_to_str(parent)?.into())
+            ))?;
+        }
+        let rsync = self.remote_rsync()?;
+        let mut command = self.copy_file_command(
+            path_to_str(local)?,
+            &self.remote_path(remote),
+            rsync.as_deref(),
+        )?;
+        check_status(&mut command)
+            .with_context(|| format!("Error pushing {} to {}", local.display(), self.id))
+    }
+
+    fn install_runnables(
+        &self,
+        project: &Project,
+        build: &Build,
+        args: &[&str],
+        envs: &[&str],
+    ) -> Result<Vec<(BuildBundle, String)>> {
+        let mut installed = vec![];
+        for runnable in &build.runnables {
+            let (_, remote_bundle) = self
+                .install_app(project, build, runnable)
+                .map_err(|e| e.context(DinghyError::InstallFailed(self.id.clone())))?;
+            let command = self.run_command(project, build, &remote_bundle, args, envs)?;
+            installed.push((remote_bundle, command_line(&command)));
+        }
+        Ok(installed)
+    }
+
     fn run_app(
         &self,
         project: &Project,
         build: &Build,
         args: &[&str],
         envs: &[&str],
-    ) -> Result<Vec<BuildBundle>> {
+    ) -> Result<(Vec<BuildBundle>, Duration)> {
+        if let Some(tool) = &build.build_args.trace {
+            self.check_remote(&format!("command -v {}", tool))
+                .with_context(|| format!("{} is not installed on {}", tool, self.id))?;
+        }
         let mut build_bundles = vec![];
-        let remote_shell_vars_as_context = |a: &str| -> Option<std::borrow::Cow<str>> {
-            self.conf.remote_shell_vars.get(a).map(|s| s.into())
-        };
-        let args: Vec<String> = args
-            .iter()
-            .map(|&a| {
-                shellexpand::full_with_context_no_errors(
-                    a,
-                    || remote_shell_vars_as_context("HOME").map(|s| PathBuf::from(&*s)),
-                    remote_shell_vars_as_context,
-                )
-            })
-            .map(|a| ::shell_escape::escape(a).to_string())
-            .collect();
+        let mut install_time = Duration::ZERO;
         for runnable in &build.runnables {
-            info!("Install {:?}", runnable.id);
-            let (build_bundle, remote_bundle) = self.install_app(&project, &build, &runnable)?;
-            debug!("Installed {:?}", runnable.id);
-            let command = format!(
-                        "cd '{}' ; {} RUST_BACKTRACE=1 DINGHY=1 LD_LIBRARY_PATH=\"{}:$LD_LIBRARY_PATH\" {} {} {}",
-                        path_to_str(&remote_bundle.bundle_dir)?,
-                        envs.join(" "),
-                        path_to_str(&remote_bundle.lib_dir)?,
-                        path_to_str(&remote_bundle.bundle_exe)?,
-                        if build.build_args.compile_mode == ::cargo::core::compiler::CompileMode::Bench { "--bench" } else { "" },
-                        args.join(" ")
-                        );
-            trace!("Ssh command: {}", command);
-            info!(
-                "Run {} on {} ({:?})",
-                runnable.id, self.id, build.build_args.compile_mode
-            );
+            let mut reboots = 0;
+            let (build_bundle, remote_bundle, status) = loop {
+                info!("Install {:?}", runnable.id);
+                let install_start = Instant::now();
+                let (build_bundle, remote_bundle) =
+                    self.install_app(&project, &build, &runnable)
+                        .map_err(|e| e.context(DinghyError::InstallFailed(self.id.clone())))?;
+                install_time += install_start.elapsed();
+                debug!("Installed {:?}", runnable.id);
+                verify_test_data(
+                    project,
+                    build,
+                    runnable,
+                    &build_bundle,
+                    &remote_bundle,
+                    &self.id,
+                    |command| {
+                        let output = self.ssh_command()?.arg(command).output()?;
+                        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+                    },
+                )?;
+                let mut ssh = self.run_command(project, build, &remote_bundle, args, envs)?;
+                info!(
+                    "Run {} on {} ({:?})",
+                    runnable.id, self.id, build.build_args.compile_mode
+                );
 
-            let status = self.ssh_command()?.arg(&command).status()?;
+                let log = open_log_file(build, &self.id, runnable)?;
+                let status = if log.is_some() || output_prefixed() {
+                    run_and_tee(&mut ssh, log)?.0
+                } else {
+                    ssh.status()?
+                };
+                if status.code() == Some(SSH_CONNECTION_ERROR)
+                    && self.conf.reconnect_after_reboot.unwrap_or(false)
+                    && reboots < MAX_REBOOTS
+                    && self.went_down()?
+                {
+                    reboots += 1;
+                    warn!(
+                        "Lost the connection to {} while running {}, waiting for it to reboot",
+                        self.id, runnable.id
+                    );
+                    self.wait_for_reconnect()?;
+                    continue;
+                }
+                break (build_bundle, remote_bundle, status);
+            };
+            if let Some(tool) = &build.build_args.trace {
+                fetch_trace(self, tool, runnable, &remote_bundle);
+            }
             if !status.success() {
-                bail!("Test failed 🐛")
+                bail!(DinghyError::RemoteCommandFailed {
+                    code: status.code()
+                })
+            }
+            if build.build_args.compile_mode == CompileMode::Bench {
+                let (remote_criterion, local_criterion) = criterion_dirs(project, &remote_bundle)?;
+                self.fetch(&remote_criterion, &local_criterion)?;
             }
 
             build_bundles.push(build_bundle);
         }
-        Ok(build_bundles)
+        Ok((build_bundles, install_time))
     }
 
-    fn start_remote_lldb(&self) -> Result<String> {
-        unimplemented!()
+    fn start_remote_lldb(&self, local_port: Option<u16>) -> Result<String> {
+        let port = match local_port {
+            Some(port) => port,
+            None => free_local_port()?,
+        };
+        let gdbserver_ports = lldb_gdbserver_ports(port)?;
+        let remote_root = self.remote_root();
+        let port_file = remote_root.join("dinghy-lldb-server.port");
+        let port_file = path_to_str(&port_file)?;
+        let quoted_port_file = ::shell_escape::escape(port_file.into()).to_string();
+        let mut command = self.ssh_command()?;
+        command.arg(format!(
+            "mkdir -p {} && rm -f {} && exec {}",
+            ::shell_escape::escape(path_to_str(&remote_root)?.into()),
+            quoted_port_file,
+            lldb_platform_command("lldb-server", &quoted_port_file, &gdbserver_ports)
+        ));
+        debug!("Running {:?}", command);
+        let mut server = command
+            .spawn()
+            .with_context(|| format!("failed to run '{:?}'", command))?;
+        let remote_port = wait_for_lldb_platform_port(|| {
+            let mut command = self.ssh_command()?;
+            command.arg(format!("cat {}", quoted_port_file));
+            Ok(String::from_utf8(command.output()?.stdout)?)
+        });
+        let remote_port = match remote_port {
+            Ok(remote_port) => remote_port,
+            Err(e) => {
+                let _ = server.kill();
+                return Err(e);
+            }
+        };
+
+        // The gdbservers keep their port number, lldb gets it from the platform
+        let forwards = std::iter::once(format!("{}:localhost:{}", port, remote_port))
+            .chain(
+                gdbserver_ports
+                    .clone()
+                    .map(|it| format!("{}:localhost:{}", it, it)),
+            )
+            .collect::<Vec<_>>();
+        let mut options = vec!["-N", "-o", "ExitOnForwardFailure=yes"];
+        for forward in &forwards {
+            options.push("-L");
+            options.push(forward);
+        }
+        let mut command = self.ssh_command_with_options(&options)?;
+        debug!("Running {:?}", command);
+        let tunnel = match command.spawn() {
+            Ok(tunnel) => tunnel,
+            Err(e) => {
+                let _ = server.kill();
+                return Err(anyhow!(e).context(format!("failed to run '{:?}'", command)));
+            }
+        };
+        *self
+            .lldb_server
+            .lock()
+            .map_err(|_| anyhow!("poisoned lock"))? = Some((
+            server,
+            tunnel,
+            lldb_platform_command("lldb-server", port_file, &gdbserver_ports),
+        ));
+        Ok(format!("localhost:{}", port))
     }
+
+    fn stop_remote_lldb(&self) -> Result<()> {
+        let server = self
+            .lldb_server
+            .lock()
+            .map_err(|_| anyhow!("poisoned lock"))?
+            .take();
+        if let Some((mut server, mut tunnel, command_line)) = server {
+            debug!("Stopping lldb-server on {}", self.id);
+            let _ = self
+                .ssh_command()?
+                .arg(format!(
+                    "pkill -f {}",
+                    ::shell_escape::escape(command_line.into())
+                ))
+                .status();
+            let _ = tunnel.kill();
+            tunnel.wait()?;
+            let _ = server.kill();
+            server.wait()?;
+        }
+        Ok(())
+    }
+}
+
+fn reconnect_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(5)).min(MAX_RECONNECT_DELAY)
 }
 
 impl Debug for SshDevice {
@@ -243,3 +918,125 @@ impl Display for SshDevice {
         write!(fmt, "{}", self.conf.hostname)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+    use crate::test_build;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn raspi() -> SshDevice {
+        SshDevice::new(
+            "raspi".to_string(),
+            SshDeviceConfiguration {
+                hostname: "raspi.local".to_string(),
+                username: "pi".to_string(),
+                port: Some(2222),
+                path: None,
+                target: None,
+                toolchain: None,
+                platform: None,
+                remote_shell_vars: HashMap::from([("HOME".to_string(), "/home/pi".to_string())]),
+                install_adhoc_rsync_local_path: None,
+                extra_library_paths: vec![],
+                transfer: None,
+                password: None,
+                password_env: None,
+                exec_prefix: None,
+                reconnect_after_reboot: None,
+                rsync_bwlimit: None,
+                parallel_sync: None,
+            },
+        )
+    }
+
+    #[test]
+    fn run_command_goes_through_ssh_with_the_device_settings() {
+        let device = raspi();
+        let project = Project::new(&Arc::new(Configuration::default()));
+        let build = test_build(vec![]);
+        let remote_bundle = BuildBundle {
+            id: "my_test".to_string(),
+            bundle_dir: PathBuf::from("/tmp/dinghy/my_test"),
+            bundle_exe: PathBuf::from("/tmp/dinghy/my_test/_dinghy_my_test"),
+            lib_dir: PathBuf::from("/tmp/dinghy/overlay"),
+            root_dir: PathBuf::from("/tmp/dinghy"),
+            test_data_dir: None,
+        };
+
+        let command = device
+            .run_command(&project, &build, &remote_bundle, &["~/data"], &["FOO=1"])
+            .unwrap();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(args[..2], ["-p", "2222"]);
+        assert_eq!(args[args.len() - 2], "pi@raspi.local");
+        let remote = args[args.len() - 1];
+        assert!(
+            remote.starts_with("cd '/tmp/dinghy/my_test' ; FOO=1 DINGHY=1 "),
+            "{}",
+            remote
+        );
+        assert!(
+            remote.ends_with(" /tmp/dinghy/my_test/_dinghy_my_test  /home/pi/data"),
+            "{}",
+            remote
+        );
+    }
+
+    #[test]
+    fn copy_file_command_quotes_the_remote_path() {
+        let device = raspi();
+        let remote = device.remote_path("/tmp/dinghy/my trace.strace");
+        assert_eq!(remote, "pi@raspi.local:'/tmp/dinghy/my trace.strace'");
+
+        let command = device
+            .copy_file_command(&remote, "trace.strace", Some("/tmp/rsync"))
+            .unwrap();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(command.get_program(), "rsync");
+        assert_eq!(
+            args,
+            [
+                "--rsync-path=/tmp/rsync",
+                "-a",
+                "-e",
+                "ssh -p 2222",
+                "pi@raspi.local:'/tmp/dinghy/my trace.strace'",
+                "trace.strace"
+            ]
+        );
+
+        let command = device
+            .copy_file_command("trace.strace", &remote, None)
+            .unwrap();
+        assert_eq!(command.get_program(), "scp");
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            args[args.len() - 2..],
+            [
+                "trace.strace",
+                "pi@raspi.local:'/tmp/dinghy/my trace.strace'"
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_backs_off_exponentially() {
+        let delays: Vec<u64> = (0..RECONNECT_ATTEMPTS)
+            .map(|it| reconnect_delay(it).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30, 30]);
+    }
+}
@@ -2,7 +2,7 @@ mod device;
 use crate::{Configuration, Device, Platform, PlatformManager, Result};
 use std::sync;
 
-use self::device::SshDevice;
+pub use self::device::SshDevice;
 
 pub struct SshDeviceManager {
     conf: sync::Arc<Configuration>,
@@ -20,12 +20,7 @@ impl PlatformManager for SshDeviceManager {
             .conf
             .ssh_devices
             .iter()
-            .map(|(k, conf)| {
-                Box::new(SshDevice {
-                    id: k.clone(),
-                    conf: conf.clone(),
-                }) as _
-            })
+            .map(|(k, conf)| Box::new(SshDevice::new(k.clone(), conf.clone())) as _)
             .collect())
     }
     fn platforms(&self) -> Result<Vec<Box<dyn Platform>>> {
@@ -1,16 +1,16 @@
 use crate::errors::*;
-use cargo::util::important_paths::find_root_manifest_for_wd;
+use crate::utils::target_dir;
 use dinghy_build::build_env::append_path_to_env;
 use dinghy_build::build_env::append_path_to_target_env;
 use dinghy_build::build_env::envify;
 use dinghy_build::build_env::set_env;
 use dinghy_build::build_env::set_target_env;
-use itertools::Itertools;
+use std::fs;
 use std::io::Write;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::path::PathBuf;
-use std::{env, fs, path};
 use walkdir::WalkDir;
 
 #[cfg(not(target_os = "windows"))]
@@ -30,6 +30,17 @@ impl Toolchain {
         Ok(())
     }
 
+    pub fn setup_cxx(&self, id: &str, cxx_command: &str) -> Result<()> {
+        let shim = create_shim(
+            &target_dir()?,
+            &self.rustc_triple,
+            id,
+            "cxx",
+            format!("{} {}", cxx_command, GLOB_ARGS).as_str(),
+        )?;
+        self.setup_tool("CXX", &shim.to_string_lossy())
+    }
+
     pub fn setup_cc(&self, _id: &str, compiler_command: &str) -> Result<()> {
         set_env("TARGET_CC", compiler_command);
         set_env(format!("CC_{}", self.rustc_triple), compiler_command);
@@ -38,7 +49,7 @@ impl Toolchain {
 
     pub fn setup_linker(&self, id: &str, linker_command: &str) -> Result<()> {
         let shim = create_shim(
-            project_root()?,
+            &target_dir()?,
             &self.rustc_triple,
             id,
             "linker",
@@ -91,12 +102,7 @@ impl ToolchainConfig {
             append_path_to_target_env(
                 "PKG_CONFIG_LIBDIR",
                 Some(&self.rustc_triple),
-                WalkDir::new(self.root.to_string_lossy().as_ref())
-                    .into_iter()
-                    .filter_map(|e| e.ok()) // Ignore unreadable files, maybe could warn...
-                    .filter(|e| e.file_name() == "pkgconfig" && e.file_type().is_dir())
-                    .map(|e| e.path().to_string_lossy().into_owned())
-                    .join(":"),
+                self.pkg_config_dirs().join(":"),
             );
         }
 
@@ -106,6 +112,22 @@ impl ToolchainConfig {
         Ok(())
     }
 
+    /// The `pkgconfig` directories of the toolchain. For musl targets, only
+    /// the ones of the sysroot, the toolchain may also carry glibc ones.
+    pub fn pkg_config_dirs(&self) -> Vec<String> {
+        let search_root = match &self.sysroot {
+            Some(sysroot) if self.rustc_triple.ends_with("-musl") => sysroot,
+            _ => &self.root,
+        };
+        WalkDir::new(search_root)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_map(|e| e.ok()) // Ignore unreadable files, maybe could warn...
+            .filter(|e| e.file_name() == "pkgconfig" && e.file_type().is_dir())
+            .map(|e| e.path().to_string_lossy().into_owned())
+            .collect()
+    }
+
     pub fn setup_sysroot(&self) {
         if let Some(sr) = &self.sysroot {
             set_env("TARGET_SYSROOT", sr);
@@ -120,15 +142,17 @@ impl ToolchainConfig {
         self.as_toolchain().setup_cc(id, compiler_command)
     }
 
+    pub fn setup_cxx(&self, id: &str, cxx_command: &str) -> Result<()> {
+        self.as_toolchain().setup_cxx(id, cxx_command)
+    }
+
     pub fn setup_linker(&self, id: &str, linker_command: &str) -> Result<()> {
         self.as_toolchain().setup_linker(id, linker_command)
     }
 
     pub fn shim_executables(&self, id: &str) -> Result<()> {
-        let wd_path =
-            ::cargo::util::important_paths::find_root_manifest_for_wd(&env::current_dir()?)?;
-        let root = wd_path.parent().ok_or_else(|| anyhow!("building at / ?"))?;
-        let shims_path = root.join("target").join(&self.rustc_triple).join(id);
+        let target_dir = target_dir()?;
+        let shims_path = target_shim_path(&target_dir, &self.rustc_triple, id);
 
         for exe in self.bin_dir.read_dir()? {
             let exe = exe?;
@@ -142,7 +166,7 @@ impl ToolchainConfig {
                 .replace(self.cc_prefix.as_str(), self.rustc_triple.as_str());
             trace!("Shim {} -> {}", exe_path, rustified_exe);
             create_shim(
-                root,
+                &target_dir,
                 self.rustc_triple.as_str(),
                 id,
                 rustified_exe,
@@ -160,14 +184,18 @@ impl ToolchainConfig {
     }
 }
 
-fn create_shim<P: AsRef<path::Path>>(
-    root: P,
+fn target_shim_path(target_dir: &Path, rustc_triple: &str, id: &str) -> PathBuf {
+    target_dir.join(rustc_triple).join(id)
+}
+
+fn create_shim(
+    target_dir: &Path,
     rustc_triple: &str,
     id: &str,
     name: &str,
     shell: &str,
 ) -> Result<PathBuf> {
-    let target_shim_path = root.as_ref().join("target").join(rustc_triple).join(id);
+    let target_shim_path = target_shim_path(target_dir, rustc_triple, id);
     fs::create_dir_all(&target_shim_path)?;
     let mut shim = target_shim_path.join(name);
     if cfg!(target_os = "windows") {
@@ -179,15 +207,43 @@ fn create_shim<P: AsRef<path::Path>>(
     }
     linker_shim.write_all(shell.as_bytes())?;
     writeln!(linker_shim, "\n")?;
+    // With -vv, failing shims print the command they ran so that it can be
+    // reproduced by hand
+    if !cfg!(target_os = "windows") && log_enabled!(log::Level::Trace) {
+        writeln!(linker_shim, "status=$?")?;
+        writeln!(linker_shim, "if [ $status -ne 0 ]; then")?;
+        writeln!(
+            linker_shim,
+            "  echo \"dinghy: $0 failed with status $status, running:\" {} >&2",
+            shell
+        )?;
+        writeln!(linker_shim, "fi")?;
+        writeln!(linker_shim, "exit $status")?;
+    }
     #[cfg(unix)]
     fs::set_permissions(&shim, PermissionsExt::from_mode(0o777))?;
     Ok(shim)
 }
 
-fn project_root() -> Result<PathBuf> {
-    let wd_path = find_root_manifest_for_wd(&env::current_dir()?)?;
-    Ok(wd_path
-        .parent()
-        .ok_or_else(|| anyhow!("building at / ?"))?
-        .to_path_buf())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shims_live_in_the_target_dir() {
+        let target = tempfile::tempdir().unwrap();
+        let shim = create_shim(
+            target.path(),
+            "some-triple",
+            "some-platform",
+            "linker",
+            "true",
+        )
+        .unwrap();
+        assert_eq!(
+            shim.parent().unwrap(),
+            target.path().join("some-triple").join("some-platform")
+        );
+        assert!(shim.exists());
+    }
 }
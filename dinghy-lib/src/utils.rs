@@ -1,10 +1,17 @@
-use crate::errors::Result;
+use crate::errors::*;
+use cargo::util::config::Config;
+use cargo::util::important_paths::find_root_manifest_for_wd;
 use clap::ArgMatches;
 use filetime::set_file_times;
 use filetime::FileTime;
+use std::env;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 pub fn arg_as_string_vec(matches: &ArgMatches, option: &str) -> Vec<String> {
     matches
@@ -38,6 +45,73 @@ pub fn copy_and_sync_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Res
     Ok(())
 }
 
+/// Directory cargo writes its artifacts to: `CARGO_TARGET_DIR` or
+/// `build.target-dir` from the cargo configuration if set, `target` at the
+/// workspace root otherwise.
+pub fn target_dir() -> Result<PathBuf> {
+    if let Some(target_dir) = Config::default()?.target_dir()? {
+        return Ok(target_dir.into_path_unlocked());
+    }
+    let wd_path = find_root_manifest_for_wd(&env::current_dir()?)?;
+    Ok(wd_path
+        .parent()
+        .ok_or_else(|| anyhow!("building at / ?"))?
+        .join("target"))
+}
+
+/// Runs `f` in a thread and gives up waiting for it after `timeout`, returning
+/// `None`. The thread is left running in that case.
+pub fn run_with_timeout<T, F>(timeout: Option<Duration>, f: F) -> Result<Option<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return f().map(Some),
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result.map(Some),
+        Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+        Err(mpsc::RecvTimeoutError::Disconnected) => bail!("Timed out thread panicked"),
+    }
+}
+
+/// The command as it would be typed in a shell, its program and arguments
+/// escaped.
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_escape::escape(arg.to_string_lossy()).to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs the command, failing with `DinghyError::CommandFailed` if it does not
+/// succeed.
+pub fn check_status(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("Couldn't run `{}`", command_line(command)))?;
+    if !status.success() {
+        bail!(DinghyError::CommandFailed {
+            command: command_line(command),
+            code: status.code(),
+        })
+    }
+    Ok(())
+}
+
+pub fn free_local_port() -> Result<u16> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port())
+}
+
 pub fn path_to_str<'a>(path: &'a Path) -> Result<&'a str> {
     Ok(path
         .to_str()
@@ -107,6 +181,7 @@ pub fn lib_name_from(file_path: &Path) -> Result<String> {
 
     let (start_index, end_index) = file_name
         .find(".so")
+        .or_else(|| file_name.strip_suffix(".a").map(|it| it.len()))
         .map(|end_index| (if file_name.starts_with("lib") { 3 } else { 0 }, end_index))
         .unwrap_or((0, file_name.len()));
 
@@ -126,3 +201,53 @@ pub fn file_name_as_str(file_path: &Path) -> Result<&str> {
         .and_then(|it| it.to_str())
         .ok_or_else(|| anyhow!("'{}' is not a valid file name", file_path.display()))?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn check_status_reports_the_command_and_exit_code() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("exit 3");
+        let error = check_status(&mut command).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`sh -c 'exit 3'` failed with exit code 3"
+        );
+        assert!(check_status(Command::new("true").arg("ignored")).is_ok());
+    }
+
+    #[test]
+    fn target_dir_follows_cargo() {
+        let project = tempfile::tempdir().unwrap();
+        let project = project.path().canonicalize().unwrap();
+        fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        crate::in_dir(&project, || {
+            let inherited = env::var_os("CARGO_TARGET_DIR");
+            env::remove_var("CARGO_TARGET_DIR");
+            assert_eq!(target_dir().unwrap(), project.join("target"));
+
+            fs::create_dir(project.join(".cargo")).unwrap();
+            fs::write(
+                project.join(".cargo/config"),
+                "[build]\ntarget-dir = \"from-config\"\n",
+            )
+            .unwrap();
+            assert_eq!(target_dir().unwrap(), project.join("from-config"));
+
+            env::set_var("CARGO_TARGET_DIR", project.join("from-env"));
+            assert_eq!(target_dir().unwrap(), project.join("from-env"));
+
+            match inherited {
+                Some(inherited) => env::set_var("CARGO_TARGET_DIR", inherited),
+                None => env::remove_var("CARGO_TARGET_DIR"),
+            }
+        });
+    }
+}
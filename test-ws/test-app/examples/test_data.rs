@@ -0,0 +1,11 @@
+extern crate dinghy_test;
+
+fn main() {
+    let license = dinghy_test::test_file_path("dinghy_license");
+    println!("Found path: {:?}", license);
+    assert!(
+        license.exists(),
+        "File dinghy_license not found: {:?}",
+        license
+    );
+}